@@ -0,0 +1,776 @@
+// src/async_socket.rs
+
+use crate::congestion::CongestionController;
+use crate::connection::{Connection, Event, RetransmitPolicy, Stats};
+use crate::pmtu::MAX_DATAGRAM_SIZE;
+use crate::state::ConnectionState;
+use crate::transport::{disable_fragmentation, dropped_as_too_large, listener_gone, Backlog, PendingDatagram, CONNECTION_QUEUE_LEN, DEMUX_POLL_INTERVAL, PENDING_QUEUE_LEN};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+/// Per-peer queues of a listener's demultiplexer, keyed by source address.
+type Routes = Arc<crate::transport::Routes<mpsc::Sender<Vec<u8>>>>;
+
+/// Routes a peer's datagrams to one connection until dropped.
+struct Registration {
+    routes: Routes,
+    peer: SocketAddr,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.routes.unregister(self.peer);
+    }
+}
+
+/// Where a connection's driver receives datagrams from.
+enum Incoming {
+    /// A UDP socket of our own, connected to the peer (the client side).
+    Connected,
+    /// Datagrams routed by a listener's demultiplexer. The peer stays
+    /// registered for as long as the driver holds this.
+    Demuxed {
+        datagrams: mpsc::Receiver<Vec<u8>>,
+        _registration: Registration,
+    },
+}
+
+/// Connection state shared between an `AsyncStyxSocket` and its driver task.
+struct Shared {
+    connection: Connection,
+    /// Task waiting in `poll_read`.
+    read_waker: Option<Waker>,
+    /// Task waiting in `poll_write`, `poll_flush` or `poll_shutdown`.
+    write_waker: Option<Waker>,
+    /// Why the driver stopped early, if it did.
+    failure: Option<std::io::Error>,
+    /// Set once the driver task has exited.
+    finished: bool,
+}
+
+impl Shared {
+    /// The error to report once the connection can no longer make progress.
+    fn take_error(&mut self) -> std::io::Error {
+        self.failure
+            .take()
+            .or_else(|| self.connection.error())
+            .unwrap_or_else(|| std::io::Error::new(ErrorKind::ConnectionAborted, "Connection driver stopped"))
+    }
+
+    fn wake_all(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Inner {
+    shared: Mutex<Shared>,
+    /// Wakes the driver after the application queued something to send.
+    driver_notify: Notify,
+    /// Wakes `connect`, `accept` and `close` after every driver step.
+    state_changed: Notify,
+}
+
+/// A Styx connection driven by the tokio runtime.
+///
+/// Each connection runs a driver task that owns its timers and datagram I/O,
+/// so reads and writes never block the runtime. Data is exchanged through the
+/// `AsyncRead` and `AsyncWrite` implementations; `poll_shutdown` sends our FIN
+/// and completes once the peer acknowledged it, while `close` waits for the
+/// whole teardown like `StyxSocket::close`. Dropping the socket stops its
+/// driver without a teardown.
+pub struct AsyncStyxSocket {
+    inner: Arc<Inner>,
+    peer_addr: SocketAddr,
+    driver: JoinHandle<()>,
+}
+
+impl AsyncStyxSocket {
+    /// Connects to a remote address.
+    pub async fn connect(addr: &str) -> std::io::Result<Self> {
+        AsyncStyxSocket::connect_with_policy(addr, RetransmitPolicy::default()).await
+    }
+
+    /// Connects to a remote address, giving up on the handshake and later
+    /// retransmissions as `policy` dictates.
+    pub async fn connect_with_policy(addr: &str, policy: RetransmitPolicy) -> std::io::Result<Self> {
+        let peer_addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "Address resolved to nothing"))?;
+        let local: SocketAddr = if peer_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let socket = UdpSocket::bind(local).await?;
+        disable_fragmentation(&socket, local)?;
+        socket.connect(peer_addr).await?;
+
+        let connection = Connection::connect(socket.local_addr()?, peer_addr, policy, Instant::now())?;
+        let connection = AsyncStyxSocket::spawn(connection, Arc::new(socket), peer_addr, Incoming::Connected);
+        connection.wait_until_open().await?;
+        Ok(connection)
+    }
+
+    /// Starts the driver task for `connection`.
+    fn spawn(connection: Connection, socket: Arc<UdpSocket>, peer_addr: SocketAddr, incoming: Incoming) -> Self {
+        let inner = Arc::new(Inner {
+            shared: Mutex::new(Shared {
+                connection,
+                read_waker: None,
+                write_waker: None,
+                failure: None,
+                finished: false,
+            }),
+            driver_notify: Notify::new(),
+            state_changed: Notify::new(),
+        });
+        let driver = tokio::spawn(drive(Arc::clone(&inner), socket, peer_addr, incoming));
+        AsyncStyxSocket { inner, peer_addr, driver }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.inner.shared.lock().unwrap()
+    }
+
+    /// Waits until `ready` returns a value, re-checking after every driver step.
+    async fn wait_for<T>(&self, mut ready: impl FnMut(&mut Shared) -> Option<T>) -> T {
+        loop {
+            let notified = self.inner.state_changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(value) = ready(&mut self.lock()) {
+                return value;
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits for the handshake to complete or fail.
+    async fn wait_until_open(&self) -> std::io::Result<()> {
+        self.wait_for(|shared| match shared.connection.state() {
+            ConnectionState::SynSent | ConnectionState::SynReceived if !shared.finished => None,
+            ConnectionState::SynSent | ConnectionState::SynReceived | ConnectionState::Closed => Some(Err(shared.take_error())),
+            _ => Some(Ok(())),
+        })
+        .await
+    }
+
+    /// Returns the socket address of the remote peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.lock().connection.state()
+    }
+
+    /// Returns the current retransmission timeout, including any backoff.
+    pub fn rto(&self) -> Duration {
+        self.lock().connection.rto()
+    }
+
+    /// Returns the smoothed round-trip time, once it has been measured.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.lock().connection.srtt()
+    }
+
+    /// Returns how many datagrams were dropped for failing their checksum or
+    /// being too short to hold a header.
+    pub fn corrupted_packets(&self) -> u64 {
+        self.lock().connection.corrupted_packets()
+    }
+
+    /// Returns the policy that decides when retransmission gives up.
+    pub fn retransmit_policy(&self) -> RetransmitPolicy {
+        self.lock().connection.retransmit_policy()
+    }
+
+    /// Sets when retransmission gives up.
+    pub fn set_retransmit_policy(&self, policy: RetransmitPolicy) {
+        self.lock().connection.set_retransmit_policy(policy);
+    }
+
+    /// Returns the maximum number of unacknowledged data packets allowed in
+    /// flight, `usize::MAX` unless capped with `set_window_size`.
+    pub fn window_size(&self) -> usize {
+        self.lock().connection.window_size()
+    }
+
+    /// Caps the send window in packets, on top of the congestion window. A
+    /// size of 1 degrades to Stop-and-Wait.
+    pub fn set_window_size(&self, size: usize) {
+        self.lock().connection.set_window_size(size);
+        self.inner.driver_notify.notify_one();
+    }
+
+    /// Returns how many bytes the congestion controller lets us have in flight.
+    pub fn congestion_window(&self) -> usize {
+        self.lock().connection.congestion_window()
+    }
+
+    /// Replaces the congestion controller of this connection.
+    pub fn set_congestion_controller(&self, controller: Box<dyn CongestionController>) {
+        self.lock().connection.set_congestion_controller(controller);
+        self.inner.driver_notify.notify_one();
+    }
+
+    /// Returns a snapshot of the round-trip, congestion and pacing state.
+    pub fn stats(&self) -> Stats {
+        self.lock().connection.stats()
+    }
+
+    /// Returns the most received data buffered for the application at once.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.lock().connection.recv_buffer_size()
+    }
+
+    /// Sets the receive buffer whose free space is advertised to the peer.
+    pub fn set_recv_buffer_size(&self, size: usize) {
+        self.lock().connection.set_recv_buffer_size(size);
+        self.inner.driver_notify.notify_one();
+    }
+
+    /// Returns whether data is acknowledged at once rather than after a delay.
+    pub fn quickack(&self) -> bool {
+        self.lock().connection.quickack()
+    }
+
+    /// Acknowledges every data segment at once instead of delaying ACKs.
+    pub fn set_quickack(&self, enabled: bool) {
+        self.lock().connection.set_quickack(enabled);
+        self.inner.driver_notify.notify_one();
+    }
+
+    /// Returns whether small packets are sent at once.
+    pub fn nodelay(&self) -> bool {
+        self.lock().connection.nodelay()
+    }
+
+    /// Sends every write at once instead of coalescing small writes while
+    /// earlier data is unacknowledged.
+    pub fn set_nodelay(&self, enabled: bool) {
+        self.lock().connection.set_nodelay(enabled, Instant::now());
+        self.inner.driver_notify.notify_one();
+    }
+
+    /// Sends `message` as one message, split into as many packets as it
+    /// takes, for `recv_message` at the peer to put back together. Waits
+    /// only while the send window is full.
+    pub async fn send_message(&self, message: &[u8]) -> std::io::Result<()> {
+        let mut sent = 0;
+        while sent < message.len() || message.is_empty() {
+            let result = self
+                .wait_for(|shared| match shared.connection.send_message(&message[sent..], Instant::now()) {
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock && !shared.finished => {
+                        self.inner.driver_notify.notify_one(); // A closed peer window starts the persist timer
+                        None
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => Some(Err(shared.take_error())),
+                    result => Some(result),
+                })
+                .await;
+            self.inner.driver_notify.notify_one();
+            sent += result?;
+        }
+        Ok(())
+    }
+
+    /// Waits for a whole message sent with `send_message` and returns it, or
+    /// `None` once the peer has closed the connection.
+    pub async fn recv_message(&self) -> std::io::Result<Option<Vec<u8>>> {
+        let result = self
+            .wait_for(|shared| match shared.connection.recv_message() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock && !shared.finished => None,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => Some(Err(shared.take_error())),
+                result => Some(result),
+            })
+            .await;
+        self.inner.driver_notify.notify_one(); // Taking data may reopen the window, reaching EOF ACKs the FIN
+        result
+    }
+
+    /// Closes the connection and waits until the teardown is complete,
+    /// including the `TimeWait` linger of an active close. After
+    /// `poll_shutdown` this only waits for the rest of the teardown.
+    pub async fn close(&mut self) -> std::io::Result<()> {
+        {
+            let mut shared = self.lock();
+            if matches!(shared.connection.state(), ConnectionState::Established | ConnectionState::CloseWait) {
+                shared.connection.close(Instant::now())?;
+            }
+        }
+        self.inner.driver_notify.notify_one();
+        self.wait_for(|shared| match shared.connection.state() {
+            ConnectionState::Closed => Some(shared.connection.error().map_or(Ok(()), Err)),
+            _ if shared.finished => Some(Err(shared.take_error())),
+            _ => None,
+        })
+        .await
+    }
+}
+
+impl Drop for AsyncStyxSocket {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+impl AsyncRead for AsyncStyxSocket {
+    /// Reads payload bytes in stream order, reaching EOF at the peer's FIN.
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut shared = self.lock();
+        let result = shared.connection.read(buf.initialize_unfilled());
+        match result {
+            Ok(amt) => {
+                buf.advance(amt);
+                drop(shared);
+                self.inner.driver_notify.notify_one(); // Reaching EOF queues the ACK for the FIN
+                Poll::Ready(Ok(()))
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                if shared.finished {
+                    return Poll::Ready(Err(shared.take_error()));
+                }
+                shared.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStyxSocket {
+    /// Sends up to one packet's worth of `buf`, waiting only while the send
+    /// window is full.
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let mut shared = self.lock();
+        let result = shared.connection.send(buf, Instant::now());
+        match result {
+            Ok(amt) => {
+                drop(shared);
+                self.inner.driver_notify.notify_one();
+                Poll::Ready(Ok(amt))
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                if shared.finished {
+                    return Poll::Ready(Err(shared.take_error()));
+                }
+                shared.write_waker = Some(cx.waker().clone());
+                drop(shared);
+                self.inner.driver_notify.notify_one(); // A closed peer window starts the persist timer
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Sends any data held back to coalesce small writes and completes once
+    /// everything written has been acknowledged by the peer.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut shared = self.lock();
+        if let Some(e) = shared.connection.error() {
+            return Poll::Ready(Err(e));
+        }
+        if shared.connection.is_flushed() {
+            return Poll::Ready(Ok(()));
+        }
+        if shared.finished {
+            return Poll::Ready(Err(shared.take_error()));
+        }
+        shared.connection.flush(Instant::now());
+        shared.write_waker = Some(cx.waker().clone());
+        drop(shared);
+        self.inner.driver_notify.notify_one();
+        Poll::Pending
+    }
+
+    /// Sends our FIN and completes once the peer has acknowledged it. Reading
+    /// continues to work until the peer's FIN arrives.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut shared = self.lock();
+        if matches!(shared.connection.state(), ConnectionState::Established | ConnectionState::CloseWait) {
+            shared.connection.close(Instant::now())?;
+            self.inner.driver_notify.notify_one();
+        }
+        if let Some(e) = shared.connection.error() {
+            return Poll::Ready(Err(e));
+        }
+        match shared.connection.state() {
+            ConnectionState::FinWait2 | ConnectionState::TimeWait | ConnectionState::Closed => Poll::Ready(Ok(())),
+            _ if shared.finished => Poll::Ready(Err(shared.take_error())),
+            _ => {
+                shared.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs one connection: sends what the core queued, then waits for a
+/// datagram, the next timer or new work from the application.
+async fn drive(inner: Arc<Inner>, socket: Arc<UdpSocket>, peer_addr: SocketAddr, mut incoming: Incoming) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let mut transmits = Vec::new();
+        let (timeout, closed) = {
+            let mut shared = inner.shared.lock().unwrap();
+            while let Some(bytes) = shared.connection.poll_transmit() {
+                transmits.push(bytes);
+            }
+            while let Some(event) = shared.connection.poll_event() {
+                let waker = match event {
+                    Event::Readable => shared.read_waker.take(),
+                    Event::Writable => shared.write_waker.take(),
+                    Event::Connected | Event::Closed | Event::Aborted => {
+                        shared.wake_all();
+                        None
+                    }
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+            (shared.connection.poll_timeout(), shared.connection.state() == ConnectionState::Closed)
+        };
+        inner.state_changed.notify_waiters();
+        for bytes in transmits {
+            match socket.send_to(&bytes, peer_addr).await {
+                Err(e) if !dropped_as_too_large(&e, bytes.len()) => return finish(&inner, Some(e)),
+                _ => {}
+            }
+        }
+        if closed {
+            return finish(&inner, None);
+        }
+
+        let timer = async {
+            match timeout {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let step = tokio::select! {
+            datagram = recv_datagram(&socket, &mut incoming, &mut buf) => match datagram {
+                Ok(datagram) => inner.shared.lock().unwrap().connection.handle_datagram(&datagram, Instant::now()),
+                Err(e) => Err(e),
+            },
+            _ = timer => inner.shared.lock().unwrap().connection.handle_timeout(Instant::now()),
+            _ = inner.driver_notify.notified() => Ok(()),
+        };
+        if let Err(e) = step {
+            return finish(&inner, Some(e));
+        }
+    }
+}
+
+/// Marks the driver as stopped and wakes everyone waiting on it.
+fn finish(inner: &Inner, failure: Option<std::io::Error>) {
+    let mut shared = inner.shared.lock().unwrap();
+    shared.failure = failure;
+    shared.finished = true;
+    shared.wake_all();
+    drop(shared);
+    inner.state_changed.notify_waiters();
+}
+
+async fn recv_datagram(socket: &UdpSocket, incoming: &mut Incoming, buf: &mut [u8]) -> std::io::Result<Vec<u8>> {
+    match incoming {
+        Incoming::Connected => {
+            let amt = socket.recv(buf).await?;
+            Ok(buf[..amt].to_vec())
+        }
+        Incoming::Demuxed { datagrams, .. } => datagrams.recv().await.ok_or_else(listener_gone),
+    }
+}
+
+/// A listening Styx socket driven by the tokio runtime.
+///
+/// Datagrams are demultiplexed by source address on a background task, so
+/// any number of accepted connections can be served concurrently. The task
+/// keeps running while accepted connections need it, even after the
+/// listener itself is dropped.
+pub struct AsyncStyxListener {
+    socket: Arc<UdpSocket>,
+    /// The listening connection that turns SYNs into new connections.
+    listener: Connection,
+    routes: Routes,
+    backlog: tokio::sync::Mutex<Backlog<mpsc::Receiver<PendingDatagram>>>,
+}
+
+impl AsyncStyxListener {
+    /// Binds the listener to a local address.
+    pub async fn bind(addr: &str) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        disable_fragmentation(&socket, socket.local_addr()?)?;
+        let socket = Arc::new(socket);
+        let listener = Connection::listen(socket.local_addr()?)?;
+        let routes = Arc::new(crate::transport::Routes::new());
+        let (pending_tx, pending_rx) = mpsc::channel(PENDING_QUEUE_LEN);
+        tokio::spawn(demultiplex(Arc::clone(&socket), Arc::clone(&routes), pending_tx));
+        Ok(AsyncStyxListener {
+            socket,
+            listener,
+            routes,
+            backlog: tokio::sync::Mutex::new(Backlog::new(pending_rx)),
+        })
+    }
+
+    /// Returns the local address the listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr()
+    }
+
+    /// Returns the policy inherited by accepted connections.
+    pub fn retransmit_policy(&self) -> RetransmitPolicy {
+        self.listener.retransmit_policy()
+    }
+
+    /// Sets the policy inherited by every connection accepted afterwards.
+    pub fn set_retransmit_policy(&mut self, policy: RetransmitPolicy) {
+        self.listener.set_retransmit_policy(policy);
+    }
+
+    /// Sets the congestion controller every accepted connection starts with a copy of.
+    pub fn set_congestion_controller(&mut self, controller: Box<dyn CongestionController>) {
+        self.listener.set_congestion_controller(controller);
+    }
+
+    /// Returns the receive buffer size inherited by accepted connections.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.listener.recv_buffer_size()
+    }
+
+    /// Sets the receive buffer size of every connection accepted afterwards.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.listener.set_recv_buffer_size(size);
+    }
+
+    /// Makes every connection accepted afterwards acknowledge data at once.
+    pub fn set_quickack(&mut self, enabled: bool) {
+        self.listener.set_quickack(enabled);
+    }
+
+    /// Makes every connection accepted afterwards send small writes at once.
+    pub fn set_nodelay(&mut self, enabled: bool) {
+        self.listener.set_nodelay(enabled, Instant::now());
+    }
+
+    /// Returns true if this listener answers SYNs with SYN cookies.
+    pub fn syn_cookies(&self) -> bool {
+        self.listener.syn_cookies()
+    }
+
+    /// Makes the listener answer SYNs statelessly with SYN cookies, as
+    /// `StyxSocket::set_syn_cookies` does for blocking listeners.
+    pub fn set_syn_cookies(&mut self, enabled: bool) {
+        self.listener.set_syn_cookies(enabled);
+    }
+
+    /// Waits for a peer to complete the 3-way handshake and returns the new
+    /// connection. Like `StyxSocket::listen_and_accept`, every call drives
+    /// all open handshakes and returns whichever completes first.
+    pub async fn accept(&self) -> std::io::Result<AsyncStyxSocket> {
+        let mut backlog = self.backlog.lock().await;
+        loop {
+            while let Some((peer, bytes)) = backlog.handshakes.poll_transmit() {
+                self.socket.send_to(&bytes, peer).await?;
+            }
+            // Wait for a datagram from a peer without a connection, or until
+            // a handshake timer is due.
+            let received = match backlog.deferred.pop_front() {
+                Some(received) => Some(received),
+                None => {
+                    let timeout = backlog.handshakes.poll_timeout();
+                    let timer = async {
+                        match timeout {
+                            Some(at) => tokio::time::sleep_until(at.into()).await,
+                            None => std::future::pending().await,
+                        }
+                    };
+                    tokio::select! {
+                        received = backlog.pending.recv() => Some(received.ok_or_else(listener_gone)?),
+                        _ = timer => None,
+                    }
+                }
+            };
+            let now = Instant::now();
+            backlog.handshakes.handle_timeout(now);
+            let connection = match received {
+                Some((src, datagram)) => backlog.handshakes.handle_datagram(&self.listener, src, &datagram, now)?,
+                None => None,
+            };
+            let mut connection = match connection {
+                Some(connection) => connection,
+                None => continue,
+            };
+            while let Some((peer, bytes)) = backlog.handshakes.poll_transmit() {
+                self.socket.send_to(&bytes, peer).await?;
+            }
+
+            let peer = connection.peer_addr().unwrap();
+            let incoming = match self.register(peer) {
+                Some(incoming) => incoming,
+                None => continue,
+            };
+            let queued = std::iter::from_fn(|| backlog.pending.try_recv().ok()).collect();
+            for datagram in backlog.take_early(peer, queued) {
+                connection.handle_datagram(&datagram, now)?;
+            }
+            return Ok(AsyncStyxSocket::spawn(connection, Arc::clone(&self.socket), peer, incoming));
+        }
+    }
+
+    /// Routes all future datagrams from `peer` to a new queue, or returns
+    /// `None` if a live connection already owns that address.
+    fn register(&self, peer: SocketAddr) -> Option<Incoming> {
+        let (tx, rx) = mpsc::channel(CONNECTION_QUEUE_LEN);
+        if !self.routes.register(peer, tx) {
+            return None;
+        }
+        let registration = Registration {
+            routes: Arc::clone(&self.routes),
+            peer,
+        };
+        Some(Incoming::Demuxed {
+            datagrams: rx,
+            _registration: registration,
+        })
+    }
+}
+
+/// Routes datagrams arriving on a listening socket to their connections, or
+/// to `pending` for `accept`. Exits once the listener and all of its
+/// connections are gone.
+async fn demultiplex(socket: Arc<UdpSocket>, routes: Routes, pending: mpsc::Sender<PendingDatagram>) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    // Once only this task holds the routes, nobody can use them.
+    while Arc::strong_count(&routes) > 1 {
+        let (amt, src) = match tokio::time::timeout(DEMUX_POLL_INTERVAL, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                eprintln!("Demultiplexer stopped: {}", e);
+                return;
+            }
+            Err(_) => continue, // Poll interval elapsed
+        };
+        // The listener may be gone while its connections live on, or too far
+        // behind, in which case the datagram is dropped.
+        routes.route(src, buf[..amt].to_vec(), |datagram| {
+            let _ = pending.try_send((src, datagram));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A listener on a free loopback port, and the address to reach it at.
+    async fn listener() -> (AsyncStyxListener, String) {
+        let listener = AsyncStyxListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().to_string();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn streams_both_ways_until_shutdown() {
+        let (listener, addr) = listener().await;
+        let (accepted, connected) = tokio::join!(listener.accept(), AsyncStyxSocket::connect(&addr));
+        let (mut server, mut client) = (accepted.unwrap(), connected.unwrap());
+        assert_eq!(server.state(), ConnectionState::Established);
+        assert_eq!(client.state(), ConnectionState::Established);
+
+        let data: Vec<u8> = (0..200_000).map(|i| (i * 7 + i / 251) as u8).collect();
+        let upload = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        };
+        let echo = async {
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            server.write_all(b"got it").await.unwrap();
+            server.shutdown().await.unwrap();
+            received
+        };
+        let (reply, received) = tokio::join!(upload, echo);
+        assert_eq!(received, data);
+        assert_eq!(reply, b"got it");
+    }
+
+    #[tokio::test]
+    async fn accepts_several_clients_on_one_listener() {
+        let (listener, addr) = listener().await;
+        let listener = Arc::new(listener);
+        let servers: Vec<_> = (0..4)
+            .map(|_| {
+                let listener = Arc::clone(&listener);
+                tokio::spawn(async move {
+                    let mut server = listener.accept().await.unwrap();
+                    let mut received = Vec::new();
+                    server.read_to_end(&mut received).await.unwrap();
+                    (server, received) // Dropping the socket would stop its ACKs
+                })
+            })
+            .collect();
+        let clients: Vec<_> = (0..4u8)
+            .map(|id| {
+                let addr = addr.clone();
+                tokio::spawn(async move {
+                    let mut client = AsyncStyxSocket::connect(&addr).await.unwrap();
+                    client.write_all(&[id; 1000]).await.unwrap();
+                    client.shutdown().await.unwrap();
+                    client // Kept alive until the server has read everything
+                })
+            })
+            .collect();
+
+        let mut peers = std::collections::HashSet::new();
+        let mut ids = Vec::new();
+        let mut accepted = Vec::new();
+        for server in servers {
+            let (server, received) = server.await.unwrap();
+            assert!(peers.insert(server.peer_addr()), "{} was accepted twice", server.peer_addr());
+            assert_eq!(received.len(), 1000);
+            assert!(received.iter().all(|&byte| byte == received[0]), "streams were mixed up");
+            ids.push(received[0]);
+            accepted.push(server);
+        }
+        ids.sort();
+        assert_eq!(ids, [0, 1, 2, 3]);
+        for client in clients {
+            client.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unanswered_syn_does_not_delay_accept() {
+        let (listener, addr) = listener().await;
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to = listener.local_addr();
+        let mut syn_sender = Connection::connect(silent.local_addr().unwrap(), to, RetransmitPolicy::default(), Instant::now()).unwrap();
+        silent.send_to(&syn_sender.poll_transmit().unwrap(), to).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await; // Let the listener see it first
+
+        let accept = tokio::time::timeout(Duration::from_secs(2), listener.accept());
+        let (accepted, connected) = tokio::join!(accept, AsyncStyxSocket::connect(&addr));
+        let accepted = accepted.expect("accept waited on the unanswered SYN").unwrap();
+        assert_eq!(accepted.state(), ConnectionState::Established);
+        assert_ne!(accepted.peer_addr(), silent.local_addr().unwrap());
+        drop(connected.unwrap());
+    }
+}
@@ -1,15 +1,13 @@
 // src/bin/client.rs
 
-use std::net::UdpSocket;
 // Note: We use 'Styx::' to refer to our library crate.
 use Styx::styx_socket::StyxSocket;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Write};
 
 const SERVER_ADDR: &str = "127.0.0.1:8081";
 const SOURCE_FILE: &str = "sample.txt";
 const DESTINATION_FILE: &str = "received_sample.txt";
-const CHUNK_SIZE: usize = 512; // 512 bytes per chunk
 
 fn main() -> std::io::Result<()> {
     println!("Attempting to connect to {}", SERVER_ADDR);
@@ -18,28 +16,17 @@ fn main() -> std::io::Result<()> {
         Ok(mut connection) => {
             println!("Successfully connected to the server.");
 
-            // 1. Send destination filename
+            // 1. Send destination filename, terminated by a newline
             println!("Sending destination filename: {}", DESTINATION_FILE);
-            connection.send(DESTINATION_FILE.as_bytes())?;
+            connection.write_all(format!("{}\n", DESTINATION_FILE).as_bytes())?;
 
-            // 2. Open and send the file in chunks
+            // 2. Stream the file over the connection
             let mut file = File::open(SOURCE_FILE)?;
-            let mut buffer = [0; CHUNK_SIZE];
             println!("Starting file transfer of '{}'...", SOURCE_FILE);
+            let bytes_sent = io::copy(&mut file, &mut connection)?;
 
-            loop {
-                let bytes_read = file.read(&mut buffer)?;
-                if bytes_read == 0 {
-                    break; // End of file
-                }
-                connection.send(&buffer[..bytes_read])?;
-            }
-
-            // 3. Send EOF signal (empty packet)
-            println!("\nFile transfer complete. Sending EOF signal.");
-            connection.send(&[])?;
-
-            // 4. Close connection
+            // 3. Close connection (the FIN marks the end of the file)
+            println!("\nFile transfer complete, {} bytes sent.", bytes_sent);
             println!("Work done. Closing connection.");
             connection.close()?;
         }
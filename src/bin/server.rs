@@ -1,69 +1,31 @@
 // src/bin/server.rs
 
-use Styx::packet::{StyxPacket, FIN};
 use Styx::styx_socket::StyxSocket;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, BufRead, BufReader, Write};
+use std::thread;
 
 const SERVER_ADDR: &str = "127.0.0.1:8081";
 
 fn main() -> std::io::Result<()> {
     println!("Server listening on {}", SERVER_ADDR);
-    let listener = StyxSocket::bind(SERVER_ADDR)?;
+    let mut listener = StyxSocket::bind(SERVER_ADDR)?;
+    listener.set_syn_cookies(true); // Spoofed SYNs cost the server nothing
 
     loop {
         match listener.listen_and_accept() {
             Ok(mut connection) => {
-                println!("\nAccepted a new connection from: {:?}", connection.peer_addr());
-                
-                let mut buffer = [0; 1024];
-                let mut file: Option<File> = None;
+                let peer = connection.peer_addr();
+                println!("\nAccepted a new connection from: {:?}", peer);
 
-                // Loop to handle this specific connection
-                'connection_loop: loop {
-                    match connection.recv(&mut buffer) {
-                        Ok(bytes_received) => {
-                            let packet = StyxPacket::from_bytes(&buffer[..bytes_received]).unwrap();
-
-                            if (packet.flags & FIN) != 0 {
-                                println!("Received FIN, starting passive close.");
-                                if let Some(mut f) = file.take() {
-                                    f.flush()?;
-                                }
-                                connection.handle_passive_close(packet)?;
-                                break 'connection_loop;
-                            }
-
-                            // First data packet is the filename
-                            if file.is_none() {
-                                let filename = String::from_utf8_lossy(&packet.payload).to_string();
-                                println!("Receiving file, will be saved as: '{}'", filename);
-                                file = Some(File::create(&filename)?);
-                                continue;
-                            }
-                            
-                            // Subsequent packets are file data
-                            if !packet.payload.is_empty() {
-                                if let Some(ref mut f) = file {
-                                    f.write_all(&packet.payload)?;
-                                }
-                            } else {
-                                // Empty packet is EOF
-                                println!("EOF received. File transfer complete.");
-                                if let Some(mut f) = file.take() {
-                                    f.flush()?;
-                                }
-                                // Now we just wait for the client to send a FIN
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Error receiving data: {}. Closing connection.", e);
-                            break 'connection_loop;
-                        }
+                // Serve each client on its own thread so the listener can keep accepting
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(&mut connection) {
+                        eprintln!("Error handling connection from {:?}: {}. Closing connection.", peer, e);
                     }
-                }
-                println!("Connection handled and closed. Ready for next connection.");
-                println!("--------------------------------------------------");
+                    println!("Connection from {:?} handled and closed.", peer);
+                    println!("--------------------------------------------------");
+                });
             }
             Err(e) => {
                 eprintln!("Failed to accept connection: {}. Waiting for new connection.", e);
@@ -71,3 +33,23 @@ fn main() -> std::io::Result<()> {
         }
     }
 }
+
+/// Receives one file: a newline-terminated filename followed by the file
+/// contents, which end when the client closes the connection.
+fn handle_connection(connection: &mut StyxSocket) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&mut *connection);
+
+    // First line is the filename
+    let mut filename = String::new();
+    reader.read_line(&mut filename)?;
+    let filename = filename.trim_end().to_string();
+    println!("Receiving file, will be saved as: '{}'", filename);
+    let mut file = File::create(&filename)?;
+
+    // Everything after it is file data, until the client's FIN
+    let bytes_received = io::copy(&mut reader, &mut file)?;
+    file.flush()?;
+    println!("Received FIN, file transfer complete ({} bytes). Starting passive close.", bytes_received);
+
+    connection.close()
+}
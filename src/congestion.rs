@@ -0,0 +1,831 @@
+// src/congestion.rs
+
+use crate::connection::MAX_PAYLOAD_SIZE;
+use crate::rtt::RttEstimator;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Segment size the congestion window is counted in, until the connection
+/// reports a larger one through `set_mss`.
+const MSS: usize = MAX_PAYLOAD_SIZE;
+/// Congestion window of a new connection (RFC 6928).
+pub const INITIAL_WINDOW: usize = 10 * MSS;
+/// Smallest window after a loss detected by duplicate ACKs, in segments (RFC 5681).
+pub const MIN_WINDOW_SEGMENTS: usize = 2;
+/// Window after a retransmission timeout, in segments (RFC 5681).
+pub const LOSS_WINDOW_SEGMENTS: usize = 1;
+/// Most one ACK grows the window by in slow start, in segments: two, so an
+/// ACK delayed to cover two of them counts fully (RFC 3465).
+const SLOW_START_LIMIT_SEGMENTS: usize = 2;
+
+/// Whether `in_flight` bytes filled a window of `cwnd`: segments are sent
+/// whole, so a window with room for less than one more counts as full.
+fn window_limited(cwnd: usize, in_flight: usize, mss: usize) -> bool {
+    in_flight + mss > cwnd
+}
+
+/// Decides how many bytes a connection may have in flight.
+///
+/// The connection does the loss detection itself: it counts duplicate ACKs,
+/// performs fast retransmit and NewReno-style recovery, and runs the
+/// retransmission timer. The controller only hears about the outcome and
+/// answers with a window. Outside of loss recovery every newly acknowledged
+/// byte is reported through `on_ack`. Model-based controllers can instead
+/// build on the delivery rate samples passed to `on_rate_sample` and ask
+/// for their packets to be spread out by returning a `pacing_rate`.
+///
+/// Implement this trait to plug in your own algorithm. Controllers are
+/// `Clone` so a listener can hand a fresh copy of its controller to every
+/// connection it accepts.
+pub trait CongestionController: Send + Sync + ControllerClone {
+    /// Bytes that may be unacknowledged at once. One packet is always
+    /// allowed while nothing is in flight, whatever this returns.
+    fn window(&self) -> usize;
+
+    /// `acked` bytes of new data were acknowledged outside of loss recovery,
+    /// with `in_flight` bytes outstanding just before. A window the sender
+    /// did not fill says nothing about the path, so loss-based controllers
+    /// should only grow it while it limits sending (RFC 7661).
+    fn on_ack(&mut self, now: Instant, acked: usize, in_flight: usize, rtt: &RttEstimator);
+
+    /// Duplicate ACKs revealed a loss. Called once per window of data, as
+    /// fast retransmit starts, with the bytes that were in flight.
+    fn on_congestion_event(&mut self, now: Instant, in_flight: usize);
+
+    /// The retransmission timer fired, so the path may have changed
+    /// entirely. Called with the bytes that were in flight.
+    fn on_retransmission_timeout(&mut self, now: Instant, in_flight: usize);
+
+    /// An ACK acknowledged new data, in or out of loss recovery.
+    fn on_rate_sample(&mut self, _now: Instant, _sample: &RateSample) {}
+
+    /// Bytes per second new data should be sent at, or `None` to send as
+    /// fast as the window allows.
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+
+    /// The connection's segment size changed, after MSS negotiation or path
+    /// MTU discovery. The window keeps its size in bytes but should from
+    /// now on grow and shrink in steps of `mss`.
+    fn set_mss(&mut self, _mss: usize) {}
+}
+
+/// What one ACK of new data revealed about the path, following the delivery
+/// rate estimation used by TCP BBR: the bytes delivered between sending the
+/// newest acknowledged segment and its ACK, over the time that took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateSample {
+    /// Bytes this ACK newly acknowledged.
+    pub acked: usize,
+    /// Bytes delivered in total, this ACK included.
+    pub delivered: u64,
+    /// What `delivered` was when the newest acknowledged segment was sent.
+    /// Once it passes the `delivered` of an earlier sample, a round trip has
+    /// gone by since that sample.
+    pub prior_delivered: u64,
+    /// Delivery rate in bytes per second, unless the interval was too short
+    /// to measure reliably.
+    pub delivery_rate: Option<u64>,
+    /// Round-trip time of the newest acknowledged segment, unless the ACK
+    /// may have been for a retransmission.
+    pub rtt: Option<Duration>,
+    /// Bytes still in flight after this ACK.
+    pub in_flight: usize,
+}
+
+/// Clones a boxed controller; implemented for every `Clone` controller.
+pub trait ControllerClone {
+    fn clone_box(&self) -> Box<dyn CongestionController>;
+}
+
+impl<T: CongestionController + Clone + 'static> ControllerClone for T {
+    fn clone_box(&self) -> Box<dyn CongestionController> {
+        Box::new(self.clone())
+    }
+}
+
+/// Classic loss-based AIMD congestion control (RFC 5681 and RFC 6582): the
+/// window doubles every round trip in slow start, grows by one segment per
+/// round trip in congestion avoidance and halves on loss.
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Bytes acknowledged since the window last grew in congestion avoidance.
+    acked_since_growth: usize,
+}
+
+impl Default for NewReno {
+    fn default() -> Self {
+        NewReno {
+            mss: MSS,
+            cwnd: INITIAL_WINDOW,
+            ssthresh: usize::MAX,
+            acked_since_growth: 0,
+        }
+    }
+}
+
+impl NewReno {
+    /// Creates a controller in slow start with the initial window.
+    pub fn new() -> Self {
+        NewReno::default()
+    }
+
+    /// Slow-start threshold: below it the window grows exponentially.
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+}
+
+impl CongestionController for NewReno {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, _now: Instant, acked: usize, in_flight: usize, _rtt: &RttEstimator) {
+        if !window_limited(self.cwnd, in_flight, self.mss) {
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked.min(SLOW_START_LIMIT_SEGMENTS * self.mss); // Slow start
+            return;
+        }
+        self.acked_since_growth += acked;
+        if self.acked_since_growth >= self.cwnd {
+            self.acked_since_growth -= self.cwnd;
+            self.cwnd += self.mss; // Congestion avoidance
+        }
+    }
+
+    fn on_congestion_event(&mut self, _now: Instant, in_flight: usize) {
+        self.ssthresh = (in_flight / 2).max(MIN_WINDOW_SEGMENTS * self.mss);
+        self.cwnd = self.ssthresh;
+        self.acked_since_growth = 0;
+    }
+
+    fn on_retransmission_timeout(&mut self, _now: Instant, in_flight: usize) {
+        self.ssthresh = (in_flight / 2).max(MIN_WINDOW_SEGMENTS * self.mss);
+        self.cwnd = LOSS_WINDOW_SEGMENTS * self.mss;
+        self.acked_since_growth = 0;
+    }
+
+    fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+    }
+}
+
+/// Scaling constant of the cubic growth function, in segments per second cubed.
+const CUBIC_C: f64 = 0.4;
+/// Multiplicative decrease factor applied on loss.
+const CUBIC_BETA: f64 = 0.7;
+
+/// CUBIC congestion control (RFC 9438). After a loss the window grows along
+/// a cubic curve in the time since that loss: quickly back towards the
+/// window where the loss happened, flat around it, then probing beyond.
+/// Growth depends on time rather than ACK rate, so long fat paths recover
+/// far faster than with NewReno. It never grows slower than NewReno would.
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Window before the last reduction, in segments.
+    w_max: f64,
+    /// Seconds the cubic curve takes to climb back to `w_max`.
+    k: f64,
+    /// Start of the current congestion avoidance epoch.
+    epoch_start: Option<Instant>,
+    /// What NewReno's window would be in this epoch, in segments.
+    w_est: f64,
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Cubic {
+            mss: MSS,
+            cwnd: INITIAL_WINDOW,
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 0.0,
+        }
+    }
+}
+
+impl Cubic {
+    /// Creates a controller in slow start with the initial window.
+    pub fn new() -> Self {
+        Cubic::default()
+    }
+
+    /// Slow-start threshold: below it the window grows exponentially.
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    /// Window in segments the cubic curve reaches `t` seconds into the epoch.
+    fn w_cubic(&self, t: f64) -> f64 {
+        CUBIC_C * (t - self.k).powi(3) + self.w_max
+    }
+
+    /// Remembers the window at a loss and shrinks it, starting a new epoch.
+    fn reduce(&mut self) {
+        let segments = self.cwnd as f64 / self.mss as f64;
+        // Fast convergence: yield bandwidth to newer flows while still shrinking.
+        self.w_max = if segments < self.w_max { segments * (1.0 + CUBIC_BETA) / 2.0 } else { segments };
+        self.ssthresh = ((self.cwnd as f64 * CUBIC_BETA) as usize).max(MIN_WINDOW_SEGMENTS * self.mss);
+        self.epoch_start = None;
+    }
+}
+
+impl CongestionController for Cubic {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, now: Instant, acked: usize, in_flight: usize, rtt: &RttEstimator) {
+        if !window_limited(self.cwnd, in_flight, self.mss) {
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked.min(SLOW_START_LIMIT_SEGMENTS * self.mss); // Slow start
+            return;
+        }
+        let cwnd = self.cwnd as f64 / self.mss as f64;
+        let epoch_start = match self.epoch_start {
+            Some(epoch_start) => epoch_start,
+            None => {
+                self.k = ((self.w_max - cwnd).max(0.0) / CUBIC_C).cbrt();
+                self.w_max = self.w_max.max(cwnd);
+                self.w_est = cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+        let srtt = rtt.srtt().unwrap_or(Duration::from_millis(100)).as_secs_f64();
+        let t = now.duration_since(epoch_start).as_secs_f64();
+
+        // Aim for where the curve will be one round trip from now, growing
+        // at most by half the window per round trip.
+        let target = self.w_cubic(t + srtt).clamp(cwnd, cwnd * 1.5);
+        let acked_segments = acked as f64 / self.mss as f64;
+        let mut next = cwnd + (target - cwnd) / cwnd * acked_segments;
+
+        // The Reno-friendly region: never fall behind standard AIMD.
+        let alpha = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+        self.w_est += alpha * acked_segments / cwnd;
+        next = next.max(self.w_est);
+        self.cwnd = ((next * self.mss as f64) as usize).max(self.cwnd);
+    }
+
+    fn on_congestion_event(&mut self, _now: Instant, _in_flight: usize) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_retransmission_timeout(&mut self, _now: Instant, _in_flight: usize) {
+        self.reduce();
+        self.cwnd = LOSS_WINDOW_SEGMENTS * self.mss;
+    }
+
+    fn set_mss(&mut self, mss: usize) {
+        // `w_max` and `w_est` count segments, so they keep their size in bytes.
+        let scale = self.mss as f64 / mss as f64;
+        self.w_max *= scale;
+        self.w_est *= scale;
+        self.mss = mss;
+    }
+}
+
+/// Pacing and window gain in `Startup`, 2/ln 2: enough to double the
+/// delivery rate every round trip.
+const BBR_HIGH_GAIN: f64 = 2.885;
+/// Window gain once the pipe is full, leaving room for delayed and
+/// aggregated ACKs.
+const BBR_CWND_GAIN: f64 = 2.0;
+/// Pacing gains `ProbeBandwidth` cycles through, one phase per min-RTT:
+/// probe for more bandwidth, drain the queue that built, then cruise.
+const BBR_PACING_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Round trips the bottleneck bandwidth filter remembers its maximum for.
+const BBR_BANDWIDTH_ROUNDS: u64 = 10;
+/// Growth that counts as the delivery rate still increasing in `Startup`.
+const BBR_FULL_BANDWIDTH_GROWTH: f64 = 1.25;
+/// Round trips without such growth after which the pipe is deemed full.
+const BBR_FULL_BANDWIDTH_ROUNDS: u32 = 3;
+/// How long a min-RTT estimate stays valid before `ProbeRtt` refreshes it.
+const BBR_MIN_RTT_EXPIRY: Duration = Duration::from_secs(10);
+/// How long `ProbeRtt` holds the window down to measure an empty queue.
+const BBR_PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+/// Smallest window BBR uses in segments, also its window during `ProbeRtt`.
+const BBR_MIN_WINDOW_SEGMENTS: usize = 4;
+
+/// The phases of the BBR state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BbrMode {
+    /// Doubling the sending rate every round trip to find the bottleneck.
+    Startup,
+    /// Emptying the queue `Startup` built at the bottleneck.
+    Drain,
+    /// Sending at the estimated bandwidth, probing now and then for more.
+    ProbeBandwidth,
+    /// Briefly shrinking the window to measure the round-trip time without a queue.
+    ProbeRtt,
+}
+
+/// Model-based congestion control after BBR (version 1). Instead of
+/// reacting to loss, it estimates the path's bottleneck bandwidth (the
+/// highest recent delivery rate) and its propagation delay (the lowest
+/// recent RTT), paces packets at that bandwidth and keeps about two
+/// bandwidth-delay products in flight. On paths with deep buffers it keeps
+/// queues, and so latency, low where loss-based control fills the buffer
+/// before backing off.
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    mode: BbrMode,
+    mss: usize,
+    cwnd: usize,
+    /// Delivery rates as (round, bytes per second), decreasing, so the front
+    /// is the highest rate of the last `BBR_BANDWIDTH_ROUNDS` round trips.
+    bandwidth_filter: VecDeque<(u64, u64)>,
+    min_rtt: Option<Duration>,
+    /// When `min_rtt` was last measured or confirmed.
+    min_rtt_stamp: Option<Instant>,
+    /// Round trips counted so far.
+    round: u64,
+    /// `delivered` at the start of the current round trip.
+    next_round_delivered: u64,
+    /// Bandwidth `Startup` last saw grow, and the rounds since it did.
+    full_bandwidth: u64,
+    full_bandwidth_rounds: u32,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    /// Phase of `ProbeBandwidth`'s gain cycle and when it began.
+    cycle_index: usize,
+    cycle_start: Option<Instant>,
+    /// When `ProbeRtt` ends, once the window has drained to its minimum.
+    probe_rtt_done: Option<Instant>,
+    /// Window to restore after `ProbeRtt`.
+    prior_cwnd: usize,
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        Bbr {
+            mode: BbrMode::Startup,
+            mss: MSS,
+            cwnd: INITIAL_WINDOW,
+            bandwidth_filter: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: None,
+            round: 0,
+            next_round_delivered: 0,
+            full_bandwidth: 0,
+            full_bandwidth_rounds: 0,
+            pacing_gain: BBR_HIGH_GAIN,
+            cwnd_gain: BBR_HIGH_GAIN,
+            cycle_index: 0,
+            cycle_start: None,
+            probe_rtt_done: None,
+            prior_cwnd: INITIAL_WINDOW,
+        }
+    }
+}
+
+impl Bbr {
+    /// Creates a controller in `Startup` with the initial window.
+    pub fn new() -> Self {
+        Bbr::default()
+    }
+
+    /// Returns the phase the controller is in.
+    pub fn mode(&self) -> BbrMode {
+        self.mode
+    }
+
+    /// Estimated bottleneck bandwidth in bytes per second, once measured.
+    pub fn bottleneck_bandwidth(&self) -> Option<u64> {
+        self.bandwidth_filter.front().map(|&(_, rate)| rate)
+    }
+
+    /// Estimated propagation round-trip time, once measured.
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+
+    /// Smallest window in bytes, also the window during `ProbeRtt`.
+    fn min_window(&self) -> usize {
+        BBR_MIN_WINDOW_SEGMENTS * self.mss
+    }
+
+    /// Whether `Startup` found the bottleneck's bandwidth.
+    fn filled_pipe(&self) -> bool {
+        self.full_bandwidth_rounds >= BBR_FULL_BANDWIDTH_ROUNDS
+    }
+
+    /// Bytes the path holds at the estimated bandwidth and min-RTT, scaled by `gain`.
+    fn bdp(&self, gain: f64) -> Option<usize> {
+        let bandwidth = self.bottleneck_bandwidth()? as f64;
+        let min_rtt = self.min_rtt?.as_secs_f64();
+        Some((bandwidth * min_rtt * gain) as usize)
+    }
+
+    fn update_bandwidth(&mut self, sample: &RateSample) {
+        let round_start = sample.prior_delivered >= self.next_round_delivered;
+        if round_start {
+            self.next_round_delivered = sample.delivered;
+            self.round += 1;
+        }
+        if let Some(rate) = sample.delivery_rate {
+            while self.bandwidth_filter.back().is_some_and(|&(_, kept)| kept <= rate) {
+                self.bandwidth_filter.pop_back();
+            }
+            self.bandwidth_filter.push_back((self.round, rate));
+        }
+        while self.bandwidth_filter.front().is_some_and(|&(round, _)| round + BBR_BANDWIDTH_ROUNDS <= self.round) {
+            self.bandwidth_filter.pop_front();
+        }
+        if round_start && self.mode == BbrMode::Startup {
+            self.check_full_pipe();
+        }
+    }
+
+    /// Leaves `Startup` once the bandwidth stopped growing for a few round
+    /// trips: the bottleneck is full and a queue is building.
+    fn check_full_pipe(&mut self) {
+        let bandwidth = self.bottleneck_bandwidth().unwrap_or(0);
+        if bandwidth as f64 >= self.full_bandwidth as f64 * BBR_FULL_BANDWIDTH_GROWTH {
+            self.full_bandwidth = bandwidth;
+            self.full_bandwidth_rounds = 0;
+            return;
+        }
+        self.full_bandwidth_rounds += 1;
+        if self.filled_pipe() {
+            self.mode = BbrMode::Drain;
+            self.pacing_gain = 1.0 / BBR_HIGH_GAIN;
+            self.cwnd_gain = BBR_HIGH_GAIN;
+        }
+    }
+
+    fn enter_probe_bandwidth(&mut self, now: Instant) {
+        self.mode = BbrMode::ProbeBandwidth;
+        self.cwnd_gain = BBR_CWND_GAIN;
+        // Start anywhere but the draining phase, so flows sharing a
+        // bottleneck do not probe in lockstep.
+        self.cycle_index = (self.round as usize % 7 + 2) % BBR_PACING_CYCLE.len();
+        self.cycle_start = Some(now);
+        self.pacing_gain = BBR_PACING_CYCLE[self.cycle_index];
+    }
+
+    /// Moves through the gain cycle, one phase per min-RTT. The draining
+    /// phase ends early once the queue is gone.
+    fn advance_cycle(&mut self, now: Instant, in_flight: usize) {
+        let phase_over = match (self.cycle_start, self.min_rtt) {
+            (Some(start), Some(min_rtt)) => now.duration_since(start) > min_rtt,
+            _ => true,
+        };
+        let drained = self.pacing_gain < 1.0 && self.bdp(1.0).is_some_and(|bdp| in_flight <= bdp);
+        if phase_over || drained {
+            self.cycle_index = (self.cycle_index + 1) % BBR_PACING_CYCLE.len();
+            self.cycle_start = Some(now);
+            self.pacing_gain = BBR_PACING_CYCLE[self.cycle_index];
+        }
+    }
+
+    /// Tracks the lowest RTT, and when it has not been seen for a while
+    /// drains the queue in `ProbeRtt` to measure it again.
+    fn update_min_rtt(&mut self, now: Instant, sample: &RateSample) {
+        let expired = self.min_rtt_stamp.is_some_and(|stamp| now.duration_since(stamp) > BBR_MIN_RTT_EXPIRY);
+        if let Some(rtt) = sample.rtt {
+            if self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt) || expired {
+                self.min_rtt = Some(rtt);
+                self.min_rtt_stamp = Some(now);
+            }
+        }
+        if expired && self.mode != BbrMode::ProbeRtt {
+            self.mode = BbrMode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.prior_cwnd = self.cwnd;
+            self.probe_rtt_done = None;
+        }
+        if self.mode != BbrMode::ProbeRtt {
+            return;
+        }
+        match self.probe_rtt_done {
+            None if sample.in_flight <= self.min_window() => self.probe_rtt_done = Some(now + BBR_PROBE_RTT_DURATION),
+            Some(done) if now >= done => {
+                self.min_rtt_stamp = Some(now);
+                self.cwnd = self.cwnd.max(self.prior_cwnd);
+                if self.filled_pipe() {
+                    self.enter_probe_bandwidth(now);
+                } else {
+                    self.mode = BbrMode::Startup;
+                    self.pacing_gain = BBR_HIGH_GAIN;
+                    self.cwnd_gain = BBR_HIGH_GAIN;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Grows the window towards `cwnd_gain` bandwidth-delay products. Until
+    /// the pipe is full it simply grows with every acknowledged byte.
+    fn update_window(&mut self, sample: &RateSample) {
+        let target = self.bdp(self.cwnd_gain).unwrap_or(INITIAL_WINDOW).max(self.min_window());
+        if self.filled_pipe() {
+            self.cwnd = (self.cwnd + sample.acked).min(target);
+        } else if self.cwnd < target || sample.delivered < INITIAL_WINDOW as u64 {
+            self.cwnd += sample.acked;
+        }
+        self.cwnd = self.cwnd.max(self.min_window());
+        if self.mode == BbrMode::ProbeRtt {
+            self.cwnd = self.cwnd.min(self.min_window());
+        }
+    }
+}
+
+impl CongestionController for Bbr {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Unused: BBR grows its window from rate samples, in recovery or not.
+    fn on_ack(&mut self, _now: Instant, _acked: usize, _in_flight: usize, _rtt: &RttEstimator) {}
+
+    /// Loss alone says little about a path with shallow buffers or random
+    /// loss, so BBR keeps its model and only the lost data is resent.
+    fn on_congestion_event(&mut self, _now: Instant, _in_flight: usize) {}
+
+    fn on_retransmission_timeout(&mut self, _now: Instant, _in_flight: usize) {
+        self.prior_cwnd = self.cwnd;
+        self.cwnd = LOSS_WINDOW_SEGMENTS * self.mss;
+    }
+
+    fn on_rate_sample(&mut self, now: Instant, sample: &RateSample) {
+        self.update_bandwidth(sample);
+        match self.mode {
+            BbrMode::Drain if self.bdp(1.0).is_none_or(|bdp| sample.in_flight <= bdp) => self.enter_probe_bandwidth(now),
+            BbrMode::ProbeBandwidth => self.advance_cycle(now, sample.in_flight),
+            _ => {}
+        }
+        self.update_min_rtt(now, sample);
+        self.update_window(sample);
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        self.bottleneck_bandwidth().map(|bandwidth| (bandwidth as f64 * self.pacing_gain) as u64)
+    }
+
+    fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn rtt(millis: u64) -> RttEstimator {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(millis));
+        rtt
+    }
+
+    /// Acknowledges one window segment by segment, keeping it full as a
+    /// sender with plenty to send would.
+    fn ack_window(controller: &mut dyn CongestionController, now: Instant, rtt: &RttEstimator) {
+        let window = controller.window();
+        for _ in 0..window / MSS {
+            let in_flight = controller.window();
+            controller.on_ack(now, MSS, in_flight, rtt);
+        }
+    }
+
+    #[test]
+    fn slow_start_doubles_the_window_every_round_trip() {
+        let (now, rtt) = (Instant::now(), rtt(100));
+        let mut reno = NewReno::new();
+        ack_window(&mut reno, now, &rtt);
+        assert_eq!(reno.window(), 2 * INITIAL_WINDOW);
+        ack_window(&mut reno, now, &rtt);
+        assert_eq!(reno.window(), 4 * INITIAL_WINDOW);
+
+        // An ACK covering many segments counts as at most two (RFC 3465).
+        reno.on_ack(now, 5 * MSS, reno.window(), &rtt);
+        assert_eq!(reno.window(), 4 * INITIAL_WINDOW + 2 * MSS);
+    }
+
+    #[test]
+    fn congestion_avoidance_adds_a_segment_per_window() {
+        let (now, rtt) = (Instant::now(), rtt(100));
+        let mut reno = NewReno::new();
+        reno.on_congestion_event(now, 20 * MSS);
+        assert_eq!((reno.window(), reno.ssthresh()), (10 * MSS, 10 * MSS));
+
+        for _ in 0..9 {
+            reno.on_ack(now, MSS, reno.window(), &rtt);
+        }
+        assert_eq!(reno.window(), 10 * MSS);
+        reno.on_ack(now, MSS, reno.window(), &rtt);
+        assert_eq!(reno.window(), 11 * MSS);
+        ack_window(&mut reno, now, &rtt);
+        assert_eq!(reno.window(), 12 * MSS);
+    }
+
+    #[test]
+    fn loss_shrinks_the_window() {
+        let now = Instant::now();
+        let mut reno = NewReno::new();
+        reno.on_congestion_event(now, 30 * MSS);
+        assert_eq!((reno.window(), reno.ssthresh()), (15 * MSS, 15 * MSS));
+        reno.on_congestion_event(now, MSS);
+        assert_eq!(reno.window(), MIN_WINDOW_SEGMENTS * MSS);
+
+        reno.on_retransmission_timeout(now, 30 * MSS);
+        assert_eq!(reno.window(), LOSS_WINDOW_SEGMENTS * MSS);
+        assert_eq!(reno.ssthresh(), 15 * MSS); // Slow start back up to half the old flight
+
+        let mut cubic = Cubic::new();
+        cubic.on_congestion_event(now, INITIAL_WINDOW);
+        assert_eq!(cubic.window(), 7 * MSS);
+        assert_eq!(cubic.ssthresh(), 7 * MSS);
+        cubic.on_retransmission_timeout(now, 7 * MSS);
+        assert_eq!(cubic.window(), LOSS_WINDOW_SEGMENTS * MSS);
+    }
+
+    #[test]
+    fn an_unfilled_window_does_not_grow() {
+        let (now, rtt) = (Instant::now(), rtt(100));
+        let mut reno = NewReno::new();
+        let mut cubic = Cubic::new();
+        for controller in [&mut reno as &mut dyn CongestionController, &mut cubic] {
+            for _ in 0..100 {
+                controller.on_ack(now, MSS, INITIAL_WINDOW / 2, &rtt);
+            }
+            assert_eq!(controller.window(), INITIAL_WINDOW);
+            // Room for less than a segment more is as good as full.
+            controller.on_ack(now, MSS, INITIAL_WINDOW - MSS + 1, &rtt);
+            assert_eq!(controller.window(), INITIAL_WINDOW + MSS);
+        }
+        cubic.on_congestion_event(now, cubic.window());
+        let window = cubic.window();
+        for step in 0..100 {
+            cubic.on_ack(now + ms(10 * step), MSS, window / 2, &rtt);
+        }
+        assert_eq!(cubic.window(), window);
+    }
+
+    #[test]
+    fn cubic_climbs_back_to_the_loss_window_and_then_beyond() {
+        let (start, rtt) = (Instant::now(), rtt(100));
+        let mut cubic = Cubic::new();
+        while cubic.window() < 100 * MSS {
+            ack_window(&mut cubic, start, &rtt);
+        }
+        let w_max = cubic.window() as f64 / MSS as f64;
+        cubic.on_congestion_event(start, cubic.window());
+        assert_eq!(cubic.window(), (w_max * CUBIC_BETA) as usize * MSS);
+
+        // A full window is acknowledged every 100 ms round trip.
+        let k = (w_max * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt();
+        let mut now = start;
+        let mut segments_at = |until: Duration| {
+            while now < start + until {
+                now += ms(10);
+                let window = cubic.window();
+                cubic.on_ack(now, window / 10, window, &rtt);
+            }
+            cubic.window() as f64 / MSS as f64
+        };
+        // Concave: most of the way back after half of K...
+        let early = segments_at(Duration::from_secs_f64(k / 2.0));
+        assert!(early > w_max * 0.85 && early < w_max, "{early} of {w_max}");
+        // ...then flat around the old window...
+        let plateau = segments_at(Duration::from_secs_f64(k));
+        assert!((plateau - w_max).abs() < w_max * 0.05, "{plateau} of {w_max}");
+        // ...and convex beyond it, probing for more.
+        let late = segments_at(Duration::from_secs_f64(k + 3.0));
+        let expected = CUBIC_C * 3.0f64.powi(3) + w_max;
+        assert!(late > w_max + 5.0 && (late - expected).abs() < 5.0, "{late}, expected {expected}");
+    }
+
+    /// A rate sample for an ACK of `acked` bytes that completes a round trip.
+    fn sample(delivered: &mut u64, acked: usize, rate: u64, rtt: u64, in_flight: usize) -> RateSample {
+        let prior_delivered = *delivered;
+        *delivered += acked as u64;
+        RateSample {
+            acked,
+            delivered: *delivered,
+            prior_delivered,
+            delivery_rate: Some(rate),
+            rtt: Some(ms(rtt)),
+            in_flight,
+        }
+    }
+
+    const RATE: u64 = 8_000_000;
+    /// Bandwidth-delay product of `RATE` over a 100 ms path.
+    const BDP: usize = 800_000;
+
+    /// Runs BBR through `Startup` and `Drain` at `RATE`, returning it in
+    /// `ProbeBandwidth` with the time and bytes delivered so far.
+    fn probing_bbr() -> (Bbr, Instant, u64) {
+        let (mut bbr, mut now, mut delivered) = (Bbr::new(), Instant::now(), 0);
+        for rate in [RATE / 8, RATE / 4, RATE / 2, RATE, RATE, RATE, RATE] {
+            now += ms(100);
+            bbr.on_rate_sample(now, &sample(&mut delivered, BDP, rate, 100, 2 * BDP));
+        }
+        now += ms(100);
+        bbr.on_rate_sample(now, &sample(&mut delivered, BDP, RATE, 100, BDP));
+        assert_eq!(bbr.mode(), BbrMode::ProbeBandwidth);
+        (bbr, now, delivered)
+    }
+
+    #[test]
+    fn bbr_starts_up_until_the_bandwidth_stops_growing_then_drains() {
+        let (mut bbr, mut now, mut delivered) = (Bbr::new(), Instant::now(), 0);
+        assert_eq!(bbr.pacing_rate(), None);
+        for rate in [RATE / 8, RATE / 4, RATE / 2, RATE] {
+            now += ms(100);
+            bbr.on_rate_sample(now, &sample(&mut delivered, BDP, rate, 100, 2 * BDP));
+            assert_eq!(bbr.mode(), BbrMode::Startup);
+            assert_eq!(bbr.pacing_rate(), Some((rate as f64 * BBR_HIGH_GAIN) as u64));
+        }
+        assert_eq!((bbr.bottleneck_bandwidth(), bbr.min_rtt()), (Some(RATE), Some(ms(100))));
+        assert!(bbr.window() > INITIAL_WINDOW, "the window grows with every ACK in Startup");
+
+        // Three round trips without 25% more bandwidth: the pipe is full.
+        for round in 0..BBR_FULL_BANDWIDTH_ROUNDS {
+            assert_eq!(bbr.mode(), BbrMode::Startup, "round {}", round);
+            now += ms(100);
+            bbr.on_rate_sample(now, &sample(&mut delivered, BDP, RATE, 100, 2 * BDP));
+        }
+        assert_eq!(bbr.mode(), BbrMode::Drain);
+        assert_eq!(bbr.pacing_rate(), Some((RATE as f64 * (1.0 / BBR_HIGH_GAIN)) as u64));
+
+        // Drain lasts until the queue Startup built is gone.
+        now += ms(100);
+        bbr.on_rate_sample(now, &sample(&mut delivered, BDP, RATE, 100, BDP + 1));
+        assert_eq!(bbr.mode(), BbrMode::Drain);
+        now += ms(100);
+        bbr.on_rate_sample(now, &sample(&mut delivered, BDP, RATE, 100, BDP));
+        assert_eq!(bbr.mode(), BbrMode::ProbeBandwidth);
+    }
+
+    #[test]
+    fn bbr_cycles_its_pacing_gain_and_keeps_two_bdps_in_flight() {
+        let (mut bbr, mut now, mut delivered) = probing_bbr();
+        let mut gains = Vec::new();
+        for _ in 0..BBR_PACING_CYCLE.len() {
+            now += ms(101); // One phase per min-RTT
+            bbr.on_rate_sample(now, &sample(&mut delivered, BDP, RATE, 100, 2 * BDP));
+            gains.push(bbr.pacing_rate().unwrap() as f64 / RATE as f64);
+        }
+        // Every phase once, probing up right before draining down.
+        let probe = gains.iter().position(|&gain| gain == 1.25).unwrap();
+        assert_eq!(gains[(probe + 1) % gains.len()], 0.75);
+        assert_eq!(gains.iter().filter(|&&gain| gain == 1.0).count(), 6);
+        assert_eq!(bbr.window(), (RATE as f64 * 0.1 * BBR_CWND_GAIN) as usize);
+
+        // Losses leave the model alone.
+        bbr.on_congestion_event(now, 2 * BDP);
+        assert_eq!(bbr.window(), (RATE as f64 * 0.1 * BBR_CWND_GAIN) as usize);
+        assert_eq!(bbr.bottleneck_bandwidth(), Some(RATE));
+    }
+
+    #[test]
+    fn bbr_probes_the_rtt_once_its_minimum_goes_stale() {
+        let (mut bbr, mut now, mut delivered) = probing_bbr();
+        let window = bbr.window();
+        // Queueing keeps every sample above the minimum for ten seconds.
+        let stale = now + BBR_MIN_RTT_EXPIRY;
+        while now <= stale {
+            assert_eq!(bbr.mode(), BbrMode::ProbeBandwidth);
+            now += ms(500);
+            bbr.on_rate_sample(now, &sample(&mut delivered, 0, RATE, 120, window));
+        }
+        assert_eq!(bbr.mode(), BbrMode::ProbeRtt);
+        assert_eq!(bbr.window(), BBR_MIN_WINDOW_SEGMENTS * MSS);
+        assert_eq!(bbr.pacing_rate(), Some(RATE));
+        assert_eq!(bbr.min_rtt(), Some(ms(120)));
+
+        // The window is held down for a while once the flight has drained to it.
+        now += ms(100);
+        bbr.on_rate_sample(now, &sample(&mut delivered, 0, RATE, 120, BBR_MIN_WINDOW_SEGMENTS * MSS));
+        now += BBR_PROBE_RTT_DURATION - ms(1);
+        bbr.on_rate_sample(now, &sample(&mut delivered, 0, RATE, 110, BBR_MIN_WINDOW_SEGMENTS * MSS));
+        assert_eq!(bbr.mode(), BbrMode::ProbeRtt);
+        assert_eq!(bbr.min_rtt(), Some(ms(110)));
+        now += ms(1);
+        bbr.on_rate_sample(now, &sample(&mut delivered, 0, RATE, 110, BBR_MIN_WINDOW_SEGMENTS * MSS));
+        assert_eq!(bbr.mode(), BbrMode::ProbeBandwidth);
+        assert_eq!(bbr.window(), window);
+    }
+}
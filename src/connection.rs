@@ -0,0 +1,2675 @@
+// src/connection.rs
+
+// The Styx protocol as a pure state machine. Nothing in this module touches a
+// socket or reads a clock, so it can be driven by `StyxSocket`, by the tokio
+// driver, by a network simulator or by a fuzzer alike. It prints nothing:
+// drivers learn of connects, closes and aborts from `poll_event`.
+
+use crate::congestion::{CongestionController, NewReno, RateSample};
+use crate::isn::{check_syn_cookie, generate_isn, syn_cookie};
+use crate::options::{PacketOptions, MAX_SACK_BLOCKS, WINDOW_OPTION_SIZE};
+use crate::packet::{StyxPacket, ACK, EOM, FIN, HEADER_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SYN};
+use crate::pmtu::{PathMtu, BASE_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE};
+use crate::rtt::RttEstimator;
+use crate::seq::{seq_add, seq_distance, seq_ge, seq_gt, seq_le, seq_lt, seq_sub};
+use crate::state::ConnectionState;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Shortest time the active closer lingers in `TimeWait` to re-ACK a lost final ACK.
+const TIME_WAIT_DURATION: Duration = Duration::from_secs(2);
+/// How long the active closer waits in `FinWait2` for the peer's FIN.
+const FIN_WAIT_2_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bytes of a data packet that are not payload: the header and the receive
+/// window every data packet advertises.
+const DATA_OVERHEAD: usize = HEADER_SIZE + WINDOW_OPTION_SIZE;
+/// Largest payload that fits in a `BASE_DATAGRAM_SIZE` datagram, the segment
+/// size every connection starts with and the one assumed for peers that do
+/// not announce their own.
+pub const MAX_PAYLOAD_SIZE: usize = BASE_DATAGRAM_SIZE - DATA_OVERHEAD;
+/// Largest payload we accept in one data packet, announced in the handshake.
+const MAX_SEGMENT_SIZE: usize = MAX_DATAGRAM_SIZE - DATA_OVERHEAD;
+/// Bytes of received data buffered for the application by default.
+const DEFAULT_RECV_BUFFER_SIZE: usize = 64 * 1024;
+/// Largest receive buffer we keep and largest window we honour from a peer.
+/// Keeping every window well under 2^31 bytes keeps the serial-number
+/// comparisons of the `seq` module unambiguous for all data in flight.
+pub const MAX_RECV_BUFFER_SIZE: usize = 1 << 30;
+/// Longest wait between two zero-window probes.
+const MAX_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// Duplicate ACKs that trigger a fast retransmit (RFC 5681).
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+/// Longest an ACK for in-order data is held back, hoping to cover a second
+/// segment or ride on data of our own.
+const DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(40);
+/// In-order segments after which an ACK goes out without further delay.
+const DELAYED_ACK_SEGMENTS: u32 = 2;
+/// How far behind its pacing schedule a sender may catch up in one burst,
+/// about the granularity of the drivers' timers.
+const PACING_BURST: Duration = Duration::from_millis(1);
+
+/// Limits how long a connection keeps retransmitting before it gives up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetransmitPolicy {
+    /// Retransmissions allowed in a row, without the peer acknowledging
+    /// anything new, before the connection is aborted.
+    pub max_retransmissions: u32,
+    /// Longest a packet may go unacknowledged across all of its retransmissions.
+    /// `None` relies on `max_retransmissions` alone.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetransmitPolicy {
+    fn default() -> Self {
+        RetransmitPolicy {
+            max_retransmissions: 8,
+            deadline: None,
+        }
+    }
+}
+
+/// Something that happened to a connection, reported by `poll_event`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The handshake completed and data may flow.
+    Connected,
+    /// New data, or the peer's FIN, can be read.
+    Readable,
+    /// Acknowledgments freed space in the send window.
+    Writable,
+    /// The connection finished closing.
+    Closed,
+    /// The connection was torn down because the peer stopped responding.
+    Aborted,
+}
+
+/// A snapshot of a connection's transmission state, as returned by
+/// `Connection::stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Smoothed round-trip time, once measured.
+    pub srtt: Option<Duration>,
+    /// Lowest round-trip time measured.
+    pub min_rtt: Option<Duration>,
+    /// Retransmission timeout, including any backoff.
+    pub rto: Duration,
+    /// Bytes the congestion controller lets us have in flight.
+    pub congestion_window: usize,
+    /// Bytes sent and neither acknowledged nor deemed lost.
+    pub bytes_in_flight: usize,
+    /// Bytes of our data the peer acknowledged.
+    pub bytes_delivered: u64,
+    /// Latest delivery rate measured from ACKs, in bytes per second.
+    pub delivery_rate: Option<u64>,
+    /// Rate new data is paced at, in bytes per second, or `None` when the
+    /// congestion controller does not pace.
+    pub pacing_rate: Option<u64>,
+    /// Largest payload currently put in one data packet.
+    pub mss: usize,
+    /// Largest datagram known to reach the peer.
+    pub path_mtu: usize,
+    /// Data packets resent after a timeout or fast retransmit.
+    pub retransmitted_packets: u64,
+    /// Datagrams dropped for failing their checksum or being truncated.
+    pub corrupted_packets: u64,
+}
+
+/// Delivery counters a segment remembers from when it was sent, so its ACK
+/// can be turned into a delivery rate sample.
+#[derive(Clone, Copy)]
+struct DeliveryStamp {
+    /// Bytes delivered when the segment was sent.
+    delivered: u64,
+    /// When `delivered` last grew.
+    delivered_at: Instant,
+    /// Send time of the segment whose ACK started the current interval.
+    interval_start: Instant,
+}
+
+/// A data packet that has been sent but not yet acknowledged.
+struct InFlightSegment {
+    packet: StyxPacket,
+    sent_at: Instant,
+    /// When the packet was first transmitted, for the retransmission deadline.
+    first_sent_at: Instant,
+    /// Times resent. Once non-zero its ACK is ambiguous and yields no RTT sample (Karn's rule).
+    retransmissions: u32,
+    /// Deemed lost and waiting for the congestion window to let it be resent.
+    lost: bool,
+    /// Reported received by a SACK block, so it is not resent.
+    sacked: bool,
+    /// Delivery counters as of the latest transmission.
+    stamp: DeliveryStamp,
+}
+
+impl InFlightSegment {
+    /// Cuts the segment after `len` payload bytes and returns the rest, deemed
+    /// lost, as a segment of its own that shares this one's history. Only
+    /// the rest ends a message, if the segment did.
+    fn split_off(&mut self, len: usize) -> InFlightSegment {
+        let flags = self.packet.flags;
+        self.packet.flags &= !EOM;
+        let packet = StyxPacket {
+            version: self.packet.version,
+            sequence_number: seq_add(self.packet.sequence_number, len),
+            ack_number: self.packet.ack_number,
+            flags,
+            options: self.packet.options.clone(),
+            payload: self.packet.payload.split_off(len),
+        };
+        InFlightSegment {
+            packet,
+            sent_at: self.sent_at,
+            first_sent_at: self.first_sent_at,
+            retransmissions: self.retransmissions,
+            lost: true,
+            sacked: false,
+            stamp: self.stamp,
+        }
+    }
+
+    /// Sequence space the segment occupies.
+    fn len(&self) -> usize {
+        seq_distance(self.packet.sequence_number, self.end())
+    }
+
+    /// Sequence number one past this segment, counting a FIN as one byte.
+    fn end(&self) -> u32 {
+        let fin = ((self.packet.flags & FIN) != 0) as usize;
+        seq_add(self.packet.sequence_number, self.packet.payload.len() + fin)
+    }
+}
+
+/// The segments one ACK reported delivered, cumulatively or by SACK,
+/// gathered into an RTT and delivery rate sample.
+struct DeliveredSegments {
+    /// Our `delivered` counter before the ACK.
+    delivered_before: u64,
+    /// Stamp and send time of the most recently sent segment among them.
+    newest: Option<(DeliveryStamp, Instant)>,
+    /// Whether any of them was retransmitted, which makes the ACK's RTT ambiguous.
+    ambiguous: bool,
+}
+
+impl DeliveredSegments {
+    fn record(&mut self, segment: &InFlightSegment) {
+        self.ambiguous |= segment.retransmissions > 0;
+        if self.newest.is_none_or(|(stamp, _)| segment.stamp.delivered >= stamp.delivered) {
+            self.newest = Some((segment.stamp, segment.sent_at));
+        }
+    }
+}
+
+/// What a listening connection wants done with a datagram from a peer that
+/// has no connection yet, as returned by `Connection::accept_datagram`.
+pub enum ListenerAction {
+    /// Nothing: the datagram cannot start a connection.
+    Ignore,
+    /// Send these bytes (a stateless SYN-ACK) back to the peer.
+    Reply(Vec<u8>),
+    /// Route the peer's datagrams to this new connection. It is either
+    /// waiting for the final ACK or, after a SYN cookie, already established.
+    Accept(Box<Connection>),
+}
+
+/// The SYN or SYN-ACK we are retransmitting until the handshake's reply arrives.
+struct HandshakeTimer {
+    bytes: Vec<u8>,
+    first_sent_at: Instant,
+    sent_at: Instant,
+    retransmissions: u32,
+    /// Why the connection is aborted if no reply ever comes.
+    timeout_reason: &'static str,
+}
+
+/// Loss recovery in progress: segments sent before `point` are being resent.
+struct Recovery {
+    /// Our `sequence_number` when the loss was detected; an ACK covering it ends recovery.
+    point: u32,
+    /// Whether recovery started with a retransmission timeout rather than
+    /// duplicate ACKs. The window then slow-starts instead of holding still.
+    after_timeout: bool,
+}
+
+/// Probes a peer that advertised a zero receive window, so a lost window
+/// update cannot stall the connection forever.
+struct PersistTimer {
+    /// When the next probe is due.
+    next_probe: Instant,
+    /// Probes sent since the window closed, driving the backoff.
+    probes: u32,
+    /// Probes sent since the peer last acknowledged anything.
+    unanswered: u32,
+}
+
+/// The protocol state of one Styx connection, without any I/O.
+///
+/// A driver feeds it received datagrams and the passage of time, and in
+/// return takes the datagrams it wants sent and the events it raised:
+///
+/// - `handle_datagram` for every datagram from the peer,
+/// - `handle_timeout` once `poll_timeout` has passed,
+/// - `poll_transmit` until it returns `None`, after every call above,
+/// - `poll_event` to learn when to wake readers, writers or closers.
+///
+/// Every method takes the current time instead of reading a clock, so the
+/// same core runs on blocking sockets, an async runtime or a simulation.
+/// A connection in `Listen` does not talk to a peer itself; it turns
+/// datagrams from unknown peers into new connections via `accept_datagram`.
+pub struct Connection {
+    state: ConnectionState,
+    /// Our own address, which keys ISNs and SYN cookies.
+    local_addr: SocketAddr,
+    /// The peer's address, unless we are listening.
+    peer_addr: Option<SocketAddr>,
+    sequence_number: u32,
+    ack_number: u32,
+    /// Maximum number of data packets that may be unacknowledged at once, if
+    /// the application capped it; otherwise the congestion window decides.
+    window_size: usize,
+    /// Sent data packets waiting for a cumulative ACK, oldest first.
+    in_flight: VecDeque<InFlightSegment>,
+    /// Data taken by `send` that has not gone out yet, at most one packet's worth.
+    unsent: Vec<u8>,
+    /// Largest payload we put in one data packet: the smaller of what the
+    /// peer accepts and what the path is known to carry.
+    mss: usize,
+    /// Largest payload the peer said it accepts.
+    peer_mss: usize,
+    /// Search for the largest datagram the path carries.
+    pmtu: PathMtu,
+    /// Whether a small packet goes out at once instead of waiting for
+    /// earlier data to be acknowledged.
+    nodelay: bool,
+    /// Whether `flush` asked for buffered data to go out without waiting.
+    flush_requested: bool,
+    /// Whether `close` was called while data was still buffered, so our FIN
+    /// follows once it has been sent.
+    fin_pending: bool,
+    /// Whether `unsent` ends a message, so its last packet carries `EOM`
+    /// and goes out without waiting for more data.
+    message_end: bool,
+    /// In-order packets received and ACK'd but not yet handed to the caller.
+    ready: VecDeque<StyxPacket>,
+    /// The start of a message `recv_message` is reassembling, taken out of
+    /// `ready` so a message larger than the receive buffer cannot stall it.
+    partial_message: Vec<u8>,
+    /// Segments received ahead of `ack_number`, keyed by starting byte offset.
+    out_of_order: BTreeMap<u32, StyxPacket>,
+    /// Start of the latest segment filed in `out_of_order`, whose SACK block is reported first.
+    last_out_of_order: Option<u32>,
+    /// Whether every data segment is acknowledged at once instead of after a delay.
+    quickack: bool,
+    /// When the held-back ACK for in-order data is due, if one is.
+    delayed_ack: Option<Instant>,
+    /// In-order segments received since we last acknowledged anything.
+    segments_unacked: u32,
+    /// Bytes of in-order data we buffer for the application; the receive
+    /// window we advertise is whatever part of it is still free.
+    recv_buffer_size: usize,
+    /// Bytes beyond its last ACK the peer said it can buffer.
+    peer_window: usize,
+    /// Zero-window probing while the peer's window is closed and we have data to send.
+    persist: Option<PersistTimer>,
+    /// Limits how much we send into the network.
+    congestion: Box<dyn CongestionController>,
+    /// ACKs in a row that acknowledged nothing new while data was in flight.
+    duplicate_acks: u32,
+    /// Retransmission timeouts in a row, without the peer acknowledging
+    /// anything new in between.
+    timeouts: u32,
+    /// Loss recovery in progress, if any.
+    recovery: Option<Recovery>,
+    /// Whether the peer reports SACK blocks, so losses are found from them
+    /// rather than from duplicate ACKs alone.
+    peer_sacks: bool,
+    /// Bytes of our data the peer acknowledged so far.
+    delivered: u64,
+    /// When `delivered` last grew.
+    delivered_at: Option<Instant>,
+    /// Start of the send interval the next segment's rate sample measures.
+    interval_start: Option<Instant>,
+    /// Latest delivery rate measured, in bytes per second.
+    delivery_rate: Option<u64>,
+    /// Lowest RTT measured, which filters implausibly short rate samples.
+    min_rtt: Option<Duration>,
+    /// Earliest time the pacing rate lets the next new segment leave.
+    next_send: Option<Instant>,
+    /// Whether `send` was turned away by pacing and a writer awaits `Writable`.
+    pacing_wait: bool,
+    /// Data packets resent so far.
+    retransmitted_packets: u64,
+    /// Datagrams dropped because they were truncated or failed their checksum.
+    corrupted_packets: u64,
+    /// Wire-format version agreed on during the handshake.
+    version: u8,
+    /// Round-trip time estimate driving the retransmission timeout.
+    rtt: RttEstimator,
+    /// When to stop retransmitting and abort the connection.
+    policy: RetransmitPolicy,
+    /// Pending SYN or SYN-ACK retransmission while the handshake is open.
+    handshake: Option<HandshakeTimer>,
+    /// The peer's ISN in `SynReceived`, to recognise a duplicate SYN.
+    peer_isn: u32,
+    /// When `FinWait2` gives up on the peer's FIN or `TimeWait` ends.
+    close_timer: Option<Instant>,
+    /// Why the connection was aborted, if it was.
+    error: Option<(std::io::ErrorKind, &'static str)>,
+    /// Datagrams waiting to be sent by the driver.
+    transmits: VecDeque<Vec<u8>>,
+    /// Events waiting to be picked up by the driver.
+    events: VecDeque<Event>,
+    /// Whether a listener answers SYNs statelessly with SYN cookies.
+    syn_cookies: bool,
+}
+
+impl Connection {
+    fn new(state: ConnectionState, local_addr: SocketAddr, peer_addr: Option<SocketAddr>, sequence_number: u32, ack_number: u32, version: u8) -> Self {
+        Connection {
+            state,
+            local_addr,
+            peer_addr,
+            sequence_number,
+            ack_number,
+            window_size: usize::MAX,
+            in_flight: VecDeque::new(),
+            unsent: Vec::new(),
+            mss: MAX_PAYLOAD_SIZE,
+            peer_mss: MAX_PAYLOAD_SIZE,
+            pmtu: PathMtu::new(BASE_DATAGRAM_SIZE),
+            nodelay: false,
+            flush_requested: false,
+            fin_pending: false,
+            message_end: false,
+            ready: VecDeque::new(),
+            partial_message: Vec::new(),
+            out_of_order: BTreeMap::new(),
+            last_out_of_order: None,
+            quickack: false,
+            delayed_ack: None,
+            segments_unacked: 0,
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            peer_window: MAX_RECV_BUFFER_SIZE, // Until the peer advertises one
+            persist: None,
+            congestion: Box::new(NewReno::new()),
+            duplicate_acks: 0,
+            timeouts: 0,
+            recovery: None,
+            peer_sacks: false,
+            delivered: 0,
+            delivered_at: None,
+            interval_start: None,
+            delivery_rate: None,
+            min_rtt: None,
+            next_send: None,
+            pacing_wait: false,
+            retransmitted_packets: 0,
+            corrupted_packets: 0,
+            version,
+            rtt: RttEstimator::new(),
+            policy: RetransmitPolicy::default(),
+            handshake: None,
+            peer_isn: 0,
+            close_timer: None,
+            error: None,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+            syn_cookies: false,
+        }
+    }
+
+    /// Creates a listening connection on `local`. Its settings, such as the
+    /// retransmit policy and window size, are inherited by every connection
+    /// it accepts.
+    pub fn listen(local: SocketAddr) -> std::io::Result<Self> {
+        let mut listener = Connection::new(ConnectionState::Closed, local, None, 0, 0, PROTOCOL_VERSION);
+        listener.transition(ConnectionState::Listen)?;
+        Ok(listener)
+    }
+
+    /// Creates a connection for `remote` that inherits this listener's settings.
+    fn child(&self, state: ConnectionState, remote: SocketAddr, sequence_number: u32, ack_number: u32, version: u8) -> Connection {
+        let mut child = Connection::new(state, self.local_addr, Some(remote), sequence_number, ack_number, version);
+        child.policy = self.policy;
+        child.window_size = self.window_size;
+        child.recv_buffer_size = self.recv_buffer_size;
+        child.congestion = self.congestion.clone_box();
+        child.quickack = self.quickack;
+        child.nodelay = self.nodelay;
+        child
+    }
+
+    /// Decides what a listener does with a datagram from `remote`, a peer
+    /// that has no connection yet. A SYN is answered with a SYN cookie, or
+    /// starts a connection waiting for the final ACK; an ACK echoing a valid
+    /// cookie creates an established connection. The driver routes the
+    /// peer's later datagrams to an accepted connection. Fails only if this
+    /// connection is not listening.
+    pub fn accept_datagram(&self, remote: SocketAddr, bytes: &[u8], now: Instant) -> std::io::Result<ListenerAction> {
+        self.accept_datagram_as(remote, bytes, self.syn_cookies, now)
+    }
+
+    /// `accept_datagram` for a driver that holds as many half-open
+    /// connections as it is willing to: a SYN is answered with a SYN cookie
+    /// whether or not they are enabled.
+    pub fn accept_datagram_with_cookies(&self, remote: SocketAddr, bytes: &[u8], now: Instant) -> std::io::Result<ListenerAction> {
+        self.accept_datagram_as(remote, bytes, true, now)
+    }
+
+    fn accept_datagram_as(&self, remote: SocketAddr, bytes: &[u8], syn_cookies: bool, now: Instant) -> std::io::Result<ListenerAction> {
+        if self.state != ConnectionState::Listen {
+            return Err(self.wrong_state("accept connections"));
+        }
+        let packet = match StyxPacket::from_bytes(bytes) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(ListenerAction::Ignore),
+        };
+        if packet.flags == SYN {
+            // A SYN we share no version with is ignored like any other
+            // stray datagram, so it cannot fail the listener.
+            let version = match negotiate_version(&packet) {
+                Ok(version) => version,
+                Err(_) => return Ok(ListenerAction::Ignore),
+            };
+            if syn_cookies {
+                return Ok(ListenerAction::Reply(self.syn_cookie_reply(remote, &packet, version)));
+            }
+            return Ok(ListenerAction::Accept(Box::new(self.accept(remote, &packet, version, now)?)));
+        }
+        // An ACK can only start a connection by echoing a cookie we issued,
+        // which we may have done while falling back to cookies under load.
+        if (packet.flags & (SYN | ACK)) != ACK {
+            return Ok(ListenerAction::Ignore);
+        }
+        let client_isn = seq_sub(packet.sequence_number, 1);
+        match check_syn_cookie(self.local_addr, remote, client_isn, seq_sub(packet.ack_number, 1)) {
+            Some(version) => Ok(ListenerAction::Accept(Box::new(self.accept_cookie(remote, packet, version, now)?))),
+            None => Ok(ListenerAction::Ignore),
+        }
+    }
+
+    /// Starts an active open from `local` to `remote` by queueing a SYN that
+    /// offers the highest version we support.
+    pub fn connect(local: SocketAddr, remote: SocketAddr, policy: RetransmitPolicy, now: Instant) -> std::io::Result<Self> {
+        Connection::connect_with_isn(local, remote, policy, generate_isn(local, remote), now)
+    }
+
+    /// `connect` with a given initial sequence number, so tests can start
+    /// right below the wrap.
+    fn connect_with_isn(local: SocketAddr, remote: SocketAddr, policy: RetransmitPolicy, client_isn: u32, now: Instant) -> std::io::Result<Self> {
+        let mut connection = Connection::new(ConnectionState::Closed, local, Some(remote), client_isn, 0, MIN_PROTOCOL_VERSION);
+        connection.policy = policy;
+        connection.transition(ConnectionState::SynSent)?;
+
+        let mut syn_packet = StyxPacket {
+            version: MIN_PROTOCOL_VERSION,
+            sequence_number: client_isn,
+            ack_number: 0,
+            flags: SYN,
+            options: PacketOptions::new(),
+            payload: Vec::new(),
+        };
+        syn_packet.options.set_version(PROTOCOL_VERSION).unwrap();
+        syn_packet.options.set_window(connection.recv_window()).unwrap();
+        syn_packet.options.set_mss(MAX_SEGMENT_SIZE as u16).unwrap();
+        connection.start_handshake(syn_packet.to_bytes(), "Timed out waiting for SYN-ACK", now);
+        Ok(connection)
+    }
+
+    /// Answers a SYN from `remote` statefully, queueing a SYN-ACK that is
+    /// retransmitted until the final ACK arrives.
+    fn accept(&self, remote: SocketAddr, syn_packet: &StyxPacket, negotiated_version: u8, now: Instant) -> std::io::Result<Self> {
+        let client_isn = syn_packet.sequence_number;
+        let server_isn = generate_isn(self.local_addr, remote);
+        let mut connection = self.child(ConnectionState::Listen, remote, server_isn, seq_add(client_isn, 1), negotiated_version);
+        connection.transition(ConnectionState::SynReceived)?;
+        connection.peer_isn = client_isn;
+        connection.update_peer_window(syn_packet);
+        connection.negotiate_mss(syn_packet);
+
+        let mut syn_ack_packet = StyxPacket {
+            version: MIN_PROTOCOL_VERSION,
+            sequence_number: server_isn,
+            ack_number: connection.ack_number,
+            flags: SYN | ACK,
+            options: PacketOptions::new(),
+            payload: Vec::new(),
+        };
+        syn_ack_packet.options.set_version(negotiated_version).unwrap();
+        syn_ack_packet.options.set_window(connection.recv_window()).unwrap();
+        syn_ack_packet.options.set_mss(MAX_SEGMENT_SIZE as u16).unwrap();
+        connection.start_handshake(syn_ack_packet.to_bytes(), "Timed out waiting for final ACK", now);
+        Ok(connection)
+    }
+
+    /// Creates an established connection from the ACK that returned a valid
+    /// SYN cookie. The ACK may also carry data, and repeats the client's MSS
+    /// unless it was lost and this is a data packet.
+    fn accept_cookie(&self, remote: SocketAddr, ack_packet: StyxPacket, version: u8, now: Instant) -> std::io::Result<Self> {
+        let mut connection = self.child(ConnectionState::Listen, remote, ack_packet.ack_number, ack_packet.sequence_number, version);
+        connection.negotiate_mss(&ack_packet);
+        connection.transition(ConnectionState::SynReceived)?;
+        connection.transition(ConnectionState::Established)?;
+        connection.events.push_back(Event::Connected);
+        connection.handle_packet(ack_packet, now)?;
+        Ok(connection)
+    }
+
+    /// Sends the handshake packet and arms its retransmission timer.
+    fn start_handshake(&mut self, bytes: Vec<u8>, timeout_reason: &'static str, now: Instant) {
+        self.transmits.push_back(bytes.clone());
+        self.handshake = Some(HandshakeTimer {
+            bytes,
+            first_sent_at: now,
+            sent_at: now,
+            retransmissions: 0,
+            timeout_reason,
+        });
+    }
+
+    /// Stops retransmitting the handshake packet that was just answered,
+    /// sampling the RTT if it was only sent once.
+    fn finish_handshake(&mut self, now: Instant) {
+        if let Some(handshake) = self.handshake.take() {
+            if handshake.retransmissions == 0 {
+                self.rtt.sample(now.duration_since(handshake.sent_at)); // Unambiguous only if sent once
+            }
+        }
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Moves the connection to `next`, refusing moves the state machine
+    /// does not allow.
+    fn transition(&mut self, next: ConnectionState) -> std::io::Result<()> {
+        if !self.state.can_transition_to(next) {
+            let message = format!("Invalid state transition from {:?} to {:?}", self.state, next);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+        }
+        self.state = next;
+        if next == ConnectionState::Closed {
+            self.events.push_back(Event::Closed);
+        }
+        Ok(())
+    }
+
+    /// The error for an API call the current state does not permit.
+    pub(crate) fn wrong_state(&self, action: &str) -> std::io::Error {
+        let message = format!("Cannot {} in state {:?}", action, self.state);
+        std::io::Error::new(std::io::ErrorKind::NotConnected, message)
+    }
+
+    /// Returns why the connection was aborted, if it was.
+    pub fn error(&self) -> Option<std::io::Error> {
+        self.error.map(|(kind, reason)| std::io::Error::new(kind, reason))
+    }
+
+    /// Tears the connection down locally after the peer stopped responding.
+    fn abort(&mut self, reason: &'static str) {
+        self.state = ConnectionState::Closed; // Allowed from every state
+        self.in_flight.clear();
+        self.handshake = None;
+        self.close_timer = None;
+        self.persist = None;
+        self.delayed_ack = None;
+        // Nothing buffered will ever be sent, nor the FIN behind it.
+        self.unsent.clear();
+        self.fin_pending = false;
+        self.message_end = false;
+        self.flush_requested = false;
+        self.error = Some((std::io::ErrorKind::TimedOut, reason));
+        self.events.push_back(Event::Aborted);
+    }
+
+    /// Returns the address this connection is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the peer's address, or `None` for a listener.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Returns the protocol version negotiated during the handshake.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns whether this listener answers SYNs with SYN cookies.
+    pub fn syn_cookies(&self) -> bool {
+        self.syn_cookies
+    }
+
+    /// Enables or disables SYN cookies. With them enabled, a listener keeps
+    /// no state for a SYN until the client's ACK proves its address, and so
+    /// has no SYN-ACK to retransmit: if the final ACK is lost, the client is
+    /// connected and the listener knows nothing of it until the client sends
+    /// data. A protocol where the server speaks first then hangs.
+    pub fn set_syn_cookies(&mut self, enabled: bool) {
+        self.syn_cookies = enabled;
+    }
+
+    /// Returns the current retransmission timeout, including any backoff.
+    pub fn rto(&self) -> Duration {
+        self.rtt.rto()
+    }
+
+    /// Returns the smoothed round-trip time, once it has been measured.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.rtt.srtt()
+    }
+
+    /// Returns how many datagrams were dropped for failing their checksum or
+    /// being too short to hold a header.
+    pub fn corrupted_packets(&self) -> u64 {
+        self.corrupted_packets
+    }
+
+    /// Returns the policy that decides when retransmission gives up.
+    pub fn retransmit_policy(&self) -> RetransmitPolicy {
+        self.policy
+    }
+
+    /// Sets when retransmission gives up.
+    pub fn set_retransmit_policy(&mut self, policy: RetransmitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the maximum number of unacknowledged data packets allowed in
+    /// flight, `usize::MAX` unless capped with `set_window_size`.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// Caps the send window in packets, on top of the congestion window. A
+    /// size of 1 degrades to Stop-and-Wait.
+    pub fn set_window_size(&mut self, size: usize) {
+        self.window_size = size.max(1);
+    }
+
+    /// Returns the most received data buffered for the application at once.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.recv_buffer_size
+    }
+
+    /// Sets the receive buffer. Its free space is advertised to the peer as
+    /// our receive window, so a slow reader throttles the sender instead of
+    /// being overrun. Sizes above `MAX_RECV_BUFFER_SIZE` are clamped to it.
+    /// A listener passes the size on to accepted connections.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        let window_before = self.recv_window();
+        self.recv_buffer_size = size.clamp(1, MAX_RECV_BUFFER_SIZE);
+        self.announce_window(window_before);
+    }
+
+    /// Returns how many bytes the congestion controller lets us have in flight.
+    pub fn congestion_window(&self) -> usize {
+        self.congestion.window()
+    }
+
+    /// Replaces the congestion controller, e.g. with `Cubic` or one of your
+    /// own. A listener hands a copy to every connection it accepts.
+    pub fn set_congestion_controller(&mut self, mut controller: Box<dyn CongestionController>) {
+        controller.set_mss(self.mss);
+        self.congestion = controller;
+    }
+
+    /// Returns the largest payload currently put in one data packet. It
+    /// starts at `MAX_PAYLOAD_SIZE` and grows as path MTU discovery finds
+    /// that the path carries larger datagrams, up to what the peer accepts.
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    /// Returns whether data is acknowledged at once rather than after a delay.
+    pub fn quickack(&self) -> bool {
+        self.quickack
+    }
+
+    /// Acknowledges every data segment as soon as it arrives (like
+    /// `TCP_QUICKACK`) instead of every second segment, after
+    /// `DELAYED_ACK_TIMEOUT`, or along with our own data, whichever comes
+    /// first. A listener passes the setting on to accepted connections.
+    pub fn set_quickack(&mut self, enabled: bool) {
+        self.quickack = enabled;
+        if enabled && self.delayed_ack.is_some() {
+            self.send_ack();
+        }
+    }
+
+    /// Returns whether small packets are sent at once.
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// Sends every write at once, like `TCP_NODELAY`, instead of holding
+    /// back a small packet while earlier data is unacknowledged so that
+    /// later writes can join it. A listener passes the setting on to
+    /// accepted connections.
+    pub fn set_nodelay(&mut self, enabled: bool, now: Instant) {
+        self.nodelay = enabled;
+        self.push_unsent(now);
+    }
+
+    /// Returns a snapshot of the round-trip, congestion and pacing state.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            srtt: self.rtt.srtt(),
+            min_rtt: self.min_rtt,
+            rto: self.rtt.rto(),
+            congestion_window: self.congestion.window(),
+            bytes_in_flight: self.pipe(),
+            bytes_delivered: self.delivered,
+            delivery_rate: self.delivery_rate,
+            pacing_rate: self.congestion.pacing_rate(),
+            mss: self.mss,
+            path_mtu: self.pmtu.current(),
+            retransmitted_packets: self.retransmitted_packets,
+            corrupted_packets: self.corrupted_packets,
+        }
+    }
+
+    /// Returns true once everything sent has left the send buffer and been
+    /// acknowledged.
+    pub fn is_flushed(&self) -> bool {
+        self.in_flight.is_empty() && self.unsent.is_empty() && !self.fin_pending
+    }
+
+    /// Returns the next datagram to send to the peer.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next event the driver has not seen yet.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Returns when `handle_timeout` should next be called, if any timer is running.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let rto = self.rtt.rto();
+        let deadline = self.policy.deadline;
+        let timer = |sent_at: Instant, first_sent_at: Instant| match deadline {
+            Some(d) => (sent_at + rto).min(first_sent_at + d),
+            None => sent_at + rto,
+        };
+        let handshake = self.handshake.as_ref().map(|h| timer(h.sent_at, h.first_sent_at));
+        let data = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .filter_map(|(index, s)| match (s.lost, deadline) {
+                _ if !Self::timer_running(index, s) => None,
+                (false, _) => Some(timer(s.sent_at, s.first_sent_at)),
+                (true, Some(d)) => Some(s.first_sent_at + d), // Resent once the window allows
+                (true, None) => None,
+            })
+            .min();
+        let persist = self.persist.as_ref().map(|p| p.next_probe);
+        let pacing = self.next_send.filter(|_| self.pacing_wait);
+        let pmtu = self.pmtu.poll_timeout(rto);
+        [handshake, data, persist, pacing, self.delayed_ack, pmtu, self.close_timer].into_iter().flatten().min()
+    }
+
+    /// Fires every timer that has expired by `now`: handshake and data
+    /// retransmissions, zero-window probes, the pacing delay, a delayed ACK,
+    /// path MTU probes, the `FinWait2` timeout and the end of `TimeWait`.
+    pub fn handle_timeout(&mut self, now: Instant) -> std::io::Result<()> {
+        self.retransmit_handshake(now);
+        self.retransmit_data(now);
+        self.probe_window(now);
+        self.pmtu.on_timeout(now, self.rtt.rto());
+        if self.pacing_wait && self.next_send.is_none_or(|at| now >= at) {
+            self.pacing_wait = false;
+            self.events.push_back(Event::Writable);
+        }
+        if self.delayed_ack.is_some_and(|at| now >= at) {
+            self.send_ack();
+        }
+        self.push_unsent(now);
+        if self.close_timer.is_some_and(|at| now >= at) {
+            self.close_timer = None; // TimeWait is over, or the peer never sent its FIN
+            self.transition(ConnectionState::Closed)?;
+        }
+        Ok(())
+    }
+
+    /// Resends the SYN or SYN-ACK with exponential backoff, aborting once the
+    /// retransmit policy is exhausted.
+    fn retransmit_handshake(&mut self, now: Instant) {
+        let rto = self.rtt.rto();
+        let handshake = match self.handshake.as_mut() {
+            Some(handshake) => handshake,
+            None => return,
+        };
+        let past_deadline = self.policy.deadline.is_some_and(|d| now.duration_since(handshake.first_sent_at) >= d);
+        let timer_expired = now.duration_since(handshake.sent_at) >= rto;
+        if past_deadline || (timer_expired && handshake.retransmissions >= self.policy.max_retransmissions) {
+            let reason = handshake.timeout_reason;
+            self.abort(reason);
+        } else if timer_expired {
+            self.transmits.push_back(handshake.bytes.clone());
+            handshake.sent_at = now;
+            handshake.retransmissions += 1;
+            self.rtt.backoff();
+        }
+    }
+
+    /// Handles an expired retransmission timer: everything in flight that
+    /// was not SACKed is deemed lost and resent as the collapsed congestion
+    /// window allows, oldest first. Aborts once a segment exhausts the
+    /// retransmit policy.
+    fn retransmit_data(&mut self, now: Instant) {
+        let rto = self.rtt.rto();
+        let mut expired = None;
+        for (index, segment) in self.in_flight.iter().enumerate() {
+            if !Self::timer_running(index, segment) {
+                continue;
+            }
+            let past_deadline = self.policy.deadline.is_some_and(|d| now.duration_since(segment.first_sent_at) >= d);
+            let timer_expired = !segment.lost && now.duration_since(segment.sent_at) >= rto;
+            if past_deadline || (timer_expired && self.timeouts >= self.policy.max_retransmissions) {
+                self.abort("Peer stopped acknowledging data");
+                return;
+            }
+            if timer_expired && expired.is_none() {
+                expired = Some(segment.packet.sequence_number);
+            }
+        }
+        let seq = match expired {
+            Some(seq) => seq,
+            None => return,
+        };
+        self.timeouts += 1;
+        self.detect_black_hole(seq);
+        self.congestion.on_retransmission_timeout(now, self.pipe());
+        self.rtt.backoff();
+        for (index, segment) in self.in_flight.iter_mut().enumerate() {
+            if index == 0 {
+                segment.sacked = false; // Its timer expired, so the peer dropped what it SACKed
+            }
+            segment.lost = !segment.sacked;
+        }
+        self.duplicate_acks = 0;
+        self.recovery = Some(Recovery {
+            point: self.sequence_number,
+            after_timeout: true,
+        });
+        self.retransmit_lost(now);
+    }
+
+    /// Falls back to `MAX_PAYLOAD_SIZE` once a segment larger than that timed
+    /// out for the third time in a row, in case the path MTU shrank and big
+    /// packets are dropped silently (a black hole). Path MTU discovery then
+    /// starts over.
+    fn detect_black_hole(&mut self, expired: u32) {
+        let segment = match self.in_flight.iter().find(|segment| segment.packet.sequence_number == expired) {
+            Some(segment) => segment,
+            None => return,
+        };
+        if self.timeouts < 3 || segment.packet.payload.len() <= MAX_PAYLOAD_SIZE {
+            return;
+        }
+        self.pmtu.on_black_hole();
+        self.update_mss();
+    }
+
+    /// Whether the retransmission timer of the in-flight segment at `index`
+    /// runs. SACKed segments have arrived and need none, except at the
+    /// front, in case the peer dropped it after all.
+    fn timer_running(index: usize, segment: &InFlightSegment) -> bool {
+        index == 0 || !segment.sacked
+    }
+
+    /// Resends segments deemed lost, oldest first, while the congestion
+    /// window has room and they start inside the peer's window. The first
+    /// one always goes out if nothing else is in flight.
+    fn retransmit_lost(&mut self, now: Instant) {
+        let window = self.congestion.window();
+        while let Some(index) = self.in_flight.iter().position(|segment| segment.lost) {
+            let pipe = self.pipe();
+            let offset = seq_distance(self.send_base(), self.in_flight[index].packet.sequence_number);
+            if pipe > 0 && (pipe + self.in_flight[index].len() > window || offset >= self.peer_window) {
+                break;
+            }
+            self.retransmit_segment(index, now);
+        }
+    }
+
+    /// Resends the in-flight segment at `index` with our current ACK and
+    /// window. A segment larger than the current MSS, or than what is left
+    /// of the peer's window, is split, and the rest is resent as the windows
+    /// allow. A closed window still takes a byte, which serves as a probe.
+    fn retransmit_segment(&mut self, index: usize, now: Instant) {
+        let offset = seq_distance(self.send_base(), self.in_flight[index].packet.sequence_number);
+        let fits = self.mss.min(self.peer_window.saturating_sub(offset)).max(1);
+        if self.in_flight[index].packet.payload.len() > fits {
+            let rest = self.in_flight[index].split_off(fits);
+            self.in_flight.insert(index + 1, rest);
+        }
+        let options = self.ack_options();
+        let stamp = self.delivery_stamp(now);
+        let segment = &mut self.in_flight[index];
+        segment.packet.ack_number = self.ack_number;
+        segment.packet.options = options;
+        self.transmits.push_back(segment.packet.to_bytes());
+        segment.sent_at = now;
+        segment.retransmissions += 1;
+        segment.lost = false;
+        segment.stamp = stamp;
+        self.retransmitted_packets += 1;
+        self.ack_sent();
+    }
+
+    /// Delivery counters for a segment sent at `now`. A send into an empty
+    /// pipe starts a fresh interval, so idle time is not counted against
+    /// the delivery rate.
+    fn delivery_stamp(&mut self, now: Instant) -> DeliveryStamp {
+        if self.pipe() == 0 {
+            self.delivered_at = Some(now);
+            self.interval_start = Some(now);
+        }
+        DeliveryStamp {
+            delivered: self.delivered,
+            delivered_at: self.delivered_at.unwrap_or(now),
+            interval_start: self.interval_start.unwrap_or(now),
+        }
+    }
+
+    /// Bytes in flight that are neither deemed lost nor SACKed: what the network holds.
+    fn pipe(&self) -> usize {
+        self.in_flight.iter().filter(|segment| !segment.lost && !segment.sacked).map(InFlightSegment::len).sum()
+    }
+
+    /// Asks a peer whose receive window is closed for a fresh window, with
+    /// exponential backoff. Probes are answered even while the window stays
+    /// closed, so only a peer that stops answering them aborts the connection.
+    fn probe_window(&mut self, now: Instant) {
+        let persist = match self.persist.as_mut() {
+            Some(persist) if now >= persist.next_probe => persist,
+            _ => return,
+        };
+        if persist.unanswered >= self.policy.max_retransmissions {
+            self.abort("Peer stopped answering window probes");
+            return;
+        }
+        persist.probes += 1;
+        persist.unanswered += 1;
+        persist.next_probe = now + (self.rtt.rto() * 2u32.pow(persist.probes.min(16))).min(MAX_PERSIST_INTERVAL);
+
+        // Like a TCP keepalive, the probe repeats the last acknowledged byte,
+        // which the peer drops as a duplicate and answers with its window.
+        let probe_packet = StyxPacket {
+            version: self.version,
+            sequence_number: seq_sub(self.sequence_number, 1),
+            ack_number: self.ack_number,
+            flags: ACK,
+            options: self.ack_options(),
+            payload: vec![0],
+        };
+        self.transmits.push_back(probe_packet.to_bytes());
+        self.ack_sent();
+    }
+
+    /// Processes one datagram from the peer. Corrupted datagrams are counted
+    /// and dropped so retransmission can recover them.
+    pub fn handle_datagram(&mut self, bytes: &[u8], now: Instant) -> std::io::Result<()> {
+        let packet = match StyxPacket::from_bytes(bytes) {
+            Ok(packet) => packet,
+            Err(_) => {
+                self.corrupted_packets += 1;
+                return Ok(());
+            }
+        };
+        match self.state {
+            ConnectionState::SynSent => self.handle_syn_ack(packet, now),
+            ConnectionState::SynReceived => self.handle_final_ack(packet, now),
+            ConnectionState::Closed | ConnectionState::Listen => Ok(()),
+            _ => self.handle_packet(packet, now),
+        }
+    }
+
+    /// Completes an active open once the SYN-ACK for our SYN arrives.
+    fn handle_syn_ack(&mut self, packet: StyxPacket, now: Instant) -> std::io::Result<()> {
+        let client_isn = self.sequence_number;
+        if packet.flags != (SYN | ACK) || packet.ack_number != seq_add(client_isn, 1) {
+            return Ok(());
+        }
+        self.finish_handshake(now);
+        self.version = negotiate_version(&packet)?;
+        self.update_peer_window(&packet);
+        self.negotiate_mss(&packet);
+        self.transition(ConnectionState::Established)?;
+        self.ack_number = seq_add(packet.sequence_number, 1);
+        self.sequence_number = packet.ack_number;
+
+        // 3. Send final ACK. Should it be lost, the server's SYN-ACK
+        // retransmission is answered again from `handle_packet`.
+        self.send_final_ack();
+        self.events.push_back(Event::Connected);
+        Ok(())
+    }
+
+    /// Completes a passive open once the final ACK arrives. If it was lost,
+    /// the client's first data segment acknowledges our SYN just as well.
+    fn handle_final_ack(&mut self, packet: StyxPacket, now: Instant) -> std::io::Result<()> {
+        let server_isn = self.sequence_number;
+        if packet.flags == SYN && packet.sequence_number == self.peer_isn {
+            if let Some(handshake) = &self.handshake {
+                self.transmits.push_back(handshake.bytes.clone());
+            }
+            return Ok(());
+        }
+        let acks_our_syn = (packet.flags & ACK) != 0 && (packet.flags & SYN) == 0 && packet.ack_number == seq_add(server_isn, 1);
+        if !acks_our_syn {
+            return Ok(());
+        }
+        self.finish_handshake(now);
+        self.transition(ConnectionState::Established)?;
+        self.sequence_number = seq_add(server_isn, 1); // IMPORTANT: Increment sequence number after SYN is ACK'd
+        self.events.push_back(Event::Connected);
+        self.handle_packet(packet, now)
+    }
+
+    /// Applies one packet from the peer to an open or closing connection.
+    /// Every state change driven by the peer after the handshake happens here.
+    fn handle_packet(&mut self, packet: StyxPacket, now: Instant) -> std::io::Result<()> {
+        if (packet.flags & SYN) != 0 {
+            if (packet.flags & ACK) != 0 {
+                // Our final ACK was lost and the server resent its SYN-ACK.
+                self.send_final_ack();
+            }
+            return Ok(()); // A stray SYN belongs to a handshake that is already over
+        }
+        if let Some(token) = packet.options.probe() {
+            // Probes and their answers are about the path, not the data, so
+            // neither side takes them for an ACK.
+            if packet.payload.is_empty() {
+                self.on_probe_answered(token, now);
+            } else {
+                self.answer_probe(token);
+            }
+            return Ok(());
+        }
+        if self.state == ConnectionState::TimeWait && (packet.flags & FIN) != 0 {
+            self.close_timer = Some(now + self.time_wait_linger()); // Restart the timer (RFC 793)
+        }
+        if (packet.flags & ACK) != 0 {
+            let send_base = self.send_base();
+            let current = seq_ge(packet.ack_number, send_base); // Older ACKs carry stale windows
+            let duplicate = packet.ack_number == send_base
+                && !self.in_flight.is_empty()
+                && packet.payload.is_empty()
+                && (packet.flags & FIN) == 0
+                && packet.options.window().is_none_or(|window| clamp_window(window) == self.peer_window);
+            let in_flight = self.pipe();
+            let mut delivered = DeliveredSegments {
+                delivered_before: self.delivered,
+                newest: None,
+                ambiguous: false,
+            };
+            self.release_acked(packet.ack_number, &mut delivered);
+            self.apply_sack(&packet, &mut delivered);
+            let acked = seq_distance(send_base, self.send_base());
+            if current {
+                self.update_peer_window(&packet);
+            }
+            if let Some(sample) = self.take_samples(delivered, now) {
+                self.congestion.on_rate_sample(now, &sample);
+            }
+            if acked > 0 {
+                self.on_new_ack(acked, in_flight, now);
+            } else if duplicate {
+                self.on_duplicate_ack(now);
+            }
+            if self.peer_sacks {
+                self.detect_sack_losses(now);
+            }
+            self.retransmit_lost(now); // The peer's window may have opened
+            self.push_unsent(now);
+        }
+        // The peer may be sending too, or repeating a FIN whose ACK it lost.
+        if !packet.payload.is_empty() || (packet.flags & FIN) != 0 {
+            self.process_segment(packet, now);
+        }
+
+        // Our FIN is the last thing we send, so once nothing is left it was ACK'd.
+        if self.is_flushed() {
+            match self.state {
+                ConnectionState::FinWait1 => {
+                    self.transition(ConnectionState::FinWait2)?;
+                    self.close_timer = Some(now + FIN_WAIT_2_TIMEOUT);
+                }
+                ConnectionState::Closing => self.enter_time_wait(now)?,
+                ConnectionState::LastAck => {
+                    self.transition(ConnectionState::Closed)?;
+                }
+                _ => {}
+            }
+        }
+        if matches!(self.state, ConnectionState::Established | ConnectionState::FinWait1 | ConnectionState::FinWait2) {
+            self.check_peer_fin(now)?;
+        }
+        Ok(())
+    }
+
+    /// Drops every in-flight byte below `ack_number` from the send window,
+    /// recording the segments that were not SACKed already in `delivered`.
+    fn release_acked(&mut self, ack_number: u32, delivered: &mut DeliveredSegments) {
+        if seq_gt(ack_number, self.sequence_number) {
+            return; // Acknowledges data we never sent
+        }
+        let in_flight = self.in_flight.len();
+        while let Some(segment) = self.in_flight.front_mut() {
+            if seq_le(segment.end(), ack_number) {
+                if !segment.sacked {
+                    delivered.record(segment);
+                    self.delivered += segment.len() as u64;
+                }
+                self.in_flight.pop_front();
+            } else {
+                if seq_lt(segment.packet.sequence_number, ack_number) {
+                    let acked = seq_distance(segment.packet.sequence_number, ack_number);
+                    segment.packet.payload.drain(..acked);
+                    segment.packet.sequence_number = ack_number;
+                    self.delivered += acked as u64;
+                }
+                break;
+            }
+        }
+        if self.in_flight.len() < in_flight {
+            self.events.push_back(Event::Writable);
+        }
+    }
+
+    /// Marks the in-flight segments the peer's SACK blocks report received,
+    /// recording them in `delivered`. They leave the pipe and are never resent.
+    fn apply_sack(&mut self, packet: &StyxPacket, delivered: &mut DeliveredSegments) {
+        let blocks = packet.options.sack_blocks();
+        if blocks.is_empty() {
+            return;
+        }
+        self.peer_sacks = true;
+        let mut newly_sacked = 0;
+        for segment in self.in_flight.iter_mut().filter(|segment| !segment.sacked) {
+            let start = segment.packet.sequence_number;
+            if blocks.iter().any(|&(block_start, block_end)| seq_le(block_start, start) && seq_le(segment.end(), block_end)) {
+                segment.sacked = true;
+                segment.lost = false;
+                delivered.record(segment);
+                self.delivered += segment.len() as u64;
+                newly_sacked += 1;
+            }
+        }
+        if newly_sacked > 0 {
+            self.events.push_back(Event::Writable);
+        }
+    }
+
+    /// Turns the segments an ACK delivered into samples. The most recently
+    /// sent one provides an RTT sample, unless the ACK also covers a
+    /// retransmission and so may have been triggered by it (Karn's
+    /// algorithm), and a delivery rate sample: the bytes delivered since it
+    /// was sent over the time that took.
+    fn take_samples(&mut self, delivered: DeliveredSegments, now: Instant) -> Option<RateSample> {
+        if self.delivered > delivered.delivered_before {
+            self.delivered_at = Some(now);
+        }
+        let (stamp, sent_at) = delivered.newest?;
+        let rtt = Some(now.duration_since(sent_at)).filter(|_| !delivered.ambiguous);
+        if let Some(rtt) = rtt {
+            self.rtt.sample(rtt);
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+        }
+        self.interval_start = Some(sent_at);
+
+        // Whichever is longer of the send and ACK intervals, so neither
+        // ACK compression nor a burst of sends inflates the rate.
+        let interval = sent_at.duration_since(stamp.interval_start).max(now.duration_since(stamp.delivered_at));
+        let sample_bytes = self.delivered - stamp.delivered;
+        let delivery_rate = Some(interval)
+            .filter(|&interval| !interval.is_zero() && self.min_rtt.is_none_or(|min_rtt| interval >= min_rtt))
+            .map(|interval| (sample_bytes as f64 / interval.as_secs_f64()) as u64);
+        if delivery_rate.is_some() {
+            self.delivery_rate = delivery_rate;
+        }
+        Some(RateSample {
+            acked: (self.delivered - delivered.delivered_before) as usize,
+            delivered: self.delivered,
+            prior_delivered: stamp.delivered,
+            delivery_rate,
+            rtt,
+            in_flight: self.pipe(),
+        })
+    }
+
+    /// Deems lost every segment that `DUPLICATE_ACK_THRESHOLD` segments sent
+    /// after it have overtaken, as reported by SACK, and resends them. Only
+    /// the holes are resent; a resent segment counts as lost again only if
+    /// segments sent after the retransmission overtake it too. The first
+    /// loss outside of recovery starts it, like a fast retransmit.
+    fn detect_sack_losses(&mut self, now: Instant) {
+        let in_flight = self.pipe();
+        let mut newly_lost = 0;
+        for index in 0..self.in_flight.len() {
+            let segment = &self.in_flight[index];
+            if segment.lost || segment.sacked {
+                continue;
+            }
+            // Later segments were all sent after a first transmission, but
+            // only those sent strictly after a retransmission overtook it.
+            let overtaken_by = self.in_flight.iter().skip(index + 1).filter(|later| later.sacked && (segment.retransmissions == 0 || later.sent_at > segment.sent_at)).count();
+            if overtaken_by >= DUPLICATE_ACK_THRESHOLD as usize {
+                self.in_flight[index].lost = true;
+                newly_lost += 1;
+            }
+        }
+        if newly_lost == 0 {
+            return;
+        }
+        if self.recovery.is_none() {
+            self.congestion.on_congestion_event(now, in_flight);
+            self.recovery = Some(Recovery {
+                point: self.sequence_number,
+                after_timeout: false,
+            });
+            let first_lost = self.in_flight.iter().position(|segment| segment.lost).unwrap();
+            self.retransmit_segment(first_lost, now); // Regardless of the window just reduced
+        }
+        self.retransmit_lost(now);
+    }
+
+    /// Feeds newly acknowledged bytes to loss recovery or, outside of it, to
+    /// the congestion controller, along with the bytes that were in flight
+    /// before the ACK.
+    fn on_new_ack(&mut self, acked: usize, in_flight: usize, now: Instant) {
+        // The peer is making progress, however slowly, so its silence about
+        // the rest is no reason to give up or to keep backing off.
+        self.duplicate_acks = 0;
+        self.timeouts = 0;
+        self.rtt.clear_backoff();
+        match &self.recovery {
+            Some(recovery) if seq_lt(self.send_base(), recovery.point) => {
+                if recovery.after_timeout {
+                    self.congestion.on_ack(now, acked, in_flight, &self.rtt); // Slow start while resending
+                } else if !self.in_flight.is_empty() && !self.peer_sacks {
+                    // A partial ACK: the segment after the one we resent was lost too (RFC 6582).
+                    self.retransmit_segment(0, now);
+                }
+            }
+            Some(_) => self.recovery = None,
+            None => self.congestion.on_ack(now, acked, in_flight, &self.rtt),
+        }
+        self.retransmit_lost(now);
+    }
+
+    /// Counts an ACK that acknowledged nothing new. The third in a row means
+    /// the oldest segment was lost while later ones arrived, so it is resent
+    /// at once instead of waiting for its timer (fast retransmit).
+    fn on_duplicate_ack(&mut self, now: Instant) {
+        self.duplicate_acks += 1;
+        if self.duplicate_acks != DUPLICATE_ACK_THRESHOLD || self.recovery.is_some() || self.peer_sacks {
+            return; // With SACK, `detect_sack_losses` knows better what is missing
+        }
+        self.congestion.on_congestion_event(now, self.pipe());
+        self.recovery = Some(Recovery {
+            point: self.sequence_number,
+            after_timeout: false,
+        });
+        self.retransmit_segment(0, now); // Regardless of the window just reduced
+    }
+
+    /// Takes the receive window the peer advertised along with its ACK.
+    fn update_peer_window(&mut self, packet: &StyxPacket) {
+        let window = match packet.options.window() {
+            Some(window) => clamp_window(window),
+            None => return,
+        };
+        if let Some(persist) = self.persist.as_mut() {
+            persist.unanswered = 0;
+        }
+        if window == 0 {
+            self.timeouts = 0; // Retransmissions into a closed window are probes, and this answered one
+        }
+        if window > self.peer_window {
+            self.events.push_back(Event::Writable);
+        }
+        self.peer_window = window;
+        if window > 0 {
+            self.persist = None;
+        }
+    }
+
+    /// Takes the largest payload the peer accepts from its SYN, SYN-ACK or
+    /// final ACK, `MAX_PAYLOAD_SIZE` if it does not say, and starts looking
+    /// for the largest datagram the path carries up to that size.
+    fn negotiate_mss(&mut self, packet: &StyxPacket) {
+        self.peer_mss = packet.options.mss().map_or(MAX_PAYLOAD_SIZE, |mss| (mss as usize).max(1));
+        self.pmtu = PathMtu::new(self.peer_mss + DATA_OVERHEAD);
+        self.update_mss();
+    }
+
+    /// Recomputes the segment size after the peer's MSS or the path MTU changed.
+    fn update_mss(&mut self) {
+        let mss = self.peer_mss.min(self.pmtu.current() - DATA_OVERHEAD);
+        if mss != self.mss {
+            self.mss = mss;
+            self.congestion.set_mss(mss);
+        }
+    }
+
+    /// Sends the next path MTU probe, if one is due: a packet padded to the
+    /// size being tried, which the peer answers by echoing its token. Its
+    /// payload lies below `send_base`, so a peer that does not know probes
+    /// drops it as a duplicate. A lost probe costs nothing but a timeout.
+    fn probe_path_mtu(&mut self, now: Instant) {
+        let (size, token) = match self.pmtu.next_probe() {
+            Some(probe) => probe,
+            None => return,
+        };
+        let mut options = PacketOptions::new();
+        options.set_probe(token).unwrap();
+        let padding = size - HEADER_SIZE - options.encoded_len();
+        let probe_packet = StyxPacket {
+            version: self.version,
+            sequence_number: seq_sub(self.send_base(), padding),
+            ack_number: self.ack_number,
+            flags: ACK,
+            options,
+            payload: vec![0; padding],
+        };
+        self.transmits.push_back(probe_packet.to_bytes());
+        self.pmtu.on_probe_sent(size, token, now);
+    }
+
+    /// Echoes the token of a path MTU probe that reached us.
+    fn answer_probe(&mut self, token: u32) {
+        let mut options = PacketOptions::new();
+        options.set_probe(token).unwrap();
+        let answer = StyxPacket {
+            version: self.version,
+            sequence_number: self.sequence_number,
+            ack_number: self.ack_number,
+            flags: ACK,
+            options,
+            payload: Vec::new(),
+        };
+        self.transmits.push_back(answer.to_bytes());
+    }
+
+    /// Raises the segment size once the peer answered our probe.
+    fn on_probe_answered(&mut self, token: u32, now: Instant) {
+        if self.pmtu.on_probe_acked(token, now).is_some() {
+            self.update_mss();
+        }
+    }
+
+    /// Sequence number of the oldest byte we sent that is not yet acknowledged.
+    fn send_base(&self) -> u32 {
+        self.in_flight.front().map_or(self.sequence_number, |segment| segment.packet.sequence_number)
+    }
+
+    /// Bytes of in-order data waiting for the application.
+    fn buffered(&self) -> usize {
+        self.ready.iter().map(|packet| packet.payload.len()).sum()
+    }
+
+    /// The receive window we advertise: the free part of the receive buffer.
+    fn recv_window(&self) -> u32 {
+        self.recv_buffer_size.saturating_sub(self.buffered()) as u32 // At most MAX_RECV_BUFFER_SIZE
+    }
+
+    /// The ranges held in `out_of_order`, merged, the one holding the latest
+    /// arrival first and the rest in sequence order (RFC 2018). Only pure
+    /// ACKs carry them, since data packets have no room to spare.
+    fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<(u32, u32)> = self
+            .out_of_order
+            .values()
+            .map(|packet| {
+                let fin = ((packet.flags & FIN) != 0) as usize;
+                (packet.sequence_number, seq_add(packet.sequence_number, packet.payload.len() + fin))
+            })
+            .collect();
+        ranges.sort_by_key(|&(start, _)| seq_distance(self.ack_number, start));
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+        for (start, end) in ranges {
+            match blocks.last_mut() {
+                Some(last) if seq_le(start, last.1) => {
+                    if seq_gt(end, last.1) {
+                        last.1 = end;
+                    }
+                }
+                _ => blocks.push((start, end)),
+            }
+        }
+        let latest = self.last_out_of_order.and_then(|seq| blocks.iter().position(|&(start, end)| seq_le(start, seq) && seq_lt(seq, end)));
+        if let Some(index) = latest {
+            blocks[..=index].rotate_right(1);
+        }
+        blocks.truncate(MAX_SACK_BLOCKS);
+        blocks
+    }
+
+    /// Notes that a packet carrying our current ACK is going out, which
+    /// makes any delayed ACK redundant.
+    fn ack_sent(&mut self) {
+        self.delayed_ack = None;
+        self.segments_unacked = 0;
+    }
+
+    /// Options carried by every packet that acknowledges the peer's data.
+    fn ack_options(&self) -> PacketOptions {
+        let mut options = PacketOptions::new();
+        options.set_window(self.recv_window()).unwrap();
+        options
+    }
+
+    /// Sends a window update once the application has freed enough of the
+    /// receive buffer for a full packet, after the window had shrunk below
+    /// that. Smaller openings wait, so the peer is not lured into sending
+    /// tiny segments.
+    fn announce_window(&mut self, window_before: u32) {
+        let threshold = MAX_PAYLOAD_SIZE.min(self.recv_buffer_size / 2) as u32;
+        if self.state.can_receive() && window_before < threshold && self.recv_window() >= threshold {
+            self.send_ack();
+        }
+    }
+
+    /// Files a data or FIN segment into the receive buffers and answers it
+    /// with a cumulative ACK. Data beyond the window we advertised is dropped.
+    /// In-order data is acknowledged every second segment or once the
+    /// delayed ACK timer expires; anything out of the ordinary, which the
+    /// sender needs to hear about for loss recovery, is acknowledged at once.
+    fn process_segment(&mut self, mut packet: StyxPacket, now: Instant) {
+        let seq = packet.sequence_number;
+        let window_end = seq_add(self.ack_number, self.recv_window() as usize);
+        if seq_gt(seq_add(seq, packet.payload.len()), window_end) {
+            let fits = if seq_lt(seq, window_end) { seq_distance(seq, window_end) } else { 0 };
+            packet.payload.truncate(fits);
+            packet.flags &= !(FIN | EOM); // The FIN or end of message came after the bytes we dropped
+            if packet.payload.is_empty() {
+                self.send_ack();
+                return;
+            }
+        }
+        let end = seq_add(seq, packet.payload.len());
+        let in_order = seq_le(seq, self.ack_number) && seq_gt(end, self.ack_number);
+        let delay = in_order && !self.quickack && (packet.flags & FIN) == 0 && self.out_of_order.is_empty();
+        if seq_gt(seq, self.ack_number) {
+            self.last_out_of_order = Some(seq);
+        }
+        // Duplicates that end below ack_number are dropped and simply re-ACK'd.
+        if seq_ge(end, self.ack_number) {
+            let is_longer = |held: &StyxPacket| held.payload.len() < packet.payload.len();
+            if self.out_of_order.get(&seq).is_none_or(is_longer) {
+                self.out_of_order.insert(seq, packet);
+            }
+            let ready = self.ready.len();
+            self.reassemble();
+            if self.ready.len() > ready {
+                self.events.push_back(Event::Readable);
+            }
+        }
+        if !delay {
+            self.send_ack();
+            return;
+        }
+        self.segments_unacked += 1;
+        if self.segments_unacked >= DELAYED_ACK_SEGMENTS {
+            self.send_ack();
+        } else if self.delayed_ack.is_none() {
+            self.delayed_ack = Some(now + DELAYED_ACK_TIMEOUT);
+        }
+    }
+
+    /// Moves every buffered segment that now starts at or below `ack_number`
+    /// into the ready queue, trimming bytes we already delivered. Keys are
+    /// compared in sequence space, since numeric order breaks at the wrap.
+    fn reassemble(&mut self) {
+        while let Some(seq) = self.out_of_order.keys().copied().find(|&seq| seq_le(seq, self.ack_number)) {
+            let mut packet = self.out_of_order.remove(&seq).unwrap();
+            if (packet.flags & FIN) != 0 {
+                if packet.sequence_number == self.ack_number {
+                    // The FIN is acknowledged at once, whether or not the
+                    // application is reading; it stays queued as the EOF.
+                    self.ack_number = seq_add(packet.sequence_number, 1);
+                    self.ready.push_back(packet);
+                }
+                continue;
+            }
+            let end = seq_add(packet.sequence_number, packet.payload.len());
+            if seq_le(end, self.ack_number) {
+                continue; // Entirely retransmitted data we already have
+            }
+            packet.payload.drain(..seq_distance(packet.sequence_number, self.ack_number));
+            packet.sequence_number = self.ack_number;
+            self.ack_number = end;
+            self.ready.push_back(packet);
+        }
+    }
+
+    /// Queues a pure cumulative ACK for everything received so far, with
+    /// SACK blocks for any data that arrived beyond it.
+    fn send_ack(&mut self) {
+        self.ack_sent();
+        let mut options = self.ack_options();
+        let blocks = self.sack_blocks();
+        if !blocks.is_empty() {
+            options.set_sack_blocks(&blocks).unwrap();
+        }
+        let ack_packet = StyxPacket {
+            version: self.version,
+            sequence_number: self.sequence_number,
+            ack_number: self.ack_number,
+            flags: ACK,
+            options,
+            payload: Vec::new(),
+        };
+        self.transmits.push_back(ack_packet.to_bytes());
+    }
+
+    /// Acknowledges the peer's SYN-ACK. The ACK repeats our MSS for a
+    /// listener with SYN cookies, which kept no record of our SYN.
+    fn send_final_ack(&mut self) {
+        self.ack_sent();
+        let mut options = self.ack_options();
+        options.set_mss(MAX_SEGMENT_SIZE as u16).unwrap();
+        let ack_packet = StyxPacket {
+            version: self.version,
+            sequence_number: self.sequence_number,
+            ack_number: self.ack_number,
+            flags: ACK,
+            options,
+            payload: Vec::new(),
+        };
+        self.transmits.push_back(ack_packet.to_bytes());
+    }
+
+    /// Takes up to one packet's worth of `data` into the send buffer and
+    /// sends what the windows allow. Small writes are held back while
+    /// earlier data is unacknowledged, so they coalesce into full packets
+    /// (Nagle's algorithm), unless `nodelay` is set or `flush` is called.
+    /// Fails with `WouldBlock` while a full packet is buffered and the send
+    /// window, the peer's receive window, the congestion window or pacing
+    /// keeps it from leaving.
+    pub fn send(&mut self, data: &[u8], now: Instant) -> std::io::Result<usize> {
+        self.buffer_data(data, false, now)
+    }
+
+    /// Takes up to one packet's worth of `data` like `send`, and once the last
+    /// byte of `data` has been taken marks it as the end of a message for
+    /// `recv_message` at the peer. Call it again with the rest until all of
+    /// `data` is taken. The message's last packet goes out without waiting
+    /// for more data, and nothing written afterwards joins it.
+    pub fn send_message(&mut self, data: &[u8], now: Instant) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "A message needs at least one byte"));
+        }
+        self.buffer_data(data, true, now)
+    }
+
+    /// Does the work of `send` and `send_message`: `ends_message` marks the
+    /// end of `data` as the end of a message, if all of it fits.
+    fn buffer_data(&mut self, data: &[u8], ends_message: bool, now: Instant) -> std::io::Result<usize> {
+        if let Some(e) = self.error() {
+            return Err(e);
+        }
+        if !self.state.can_send() {
+            return Err(self.wrong_state("send"));
+        }
+        if data.is_empty() {
+            return Ok(0); // No stream bytes to carry
+        }
+        if self.unsent.len() >= self.mss || self.message_end {
+            // A message ends with its packet, so later data waits for it to leave.
+            if let Some(reason) = self.push_unsent(now) {
+                return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, reason));
+            }
+        }
+        let amt = (self.mss - self.unsent.len()).min(data.len());
+        self.unsent.extend_from_slice(&data[..amt]);
+        self.message_end = ends_message && amt == data.len();
+        self.push_unsent(now); // Whatever stays buffered leaves on a later ACK
+        Ok(amt)
+    }
+
+    /// Sends a partially filled packet waiting in the send buffer without
+    /// waiting for earlier data to be acknowledged, as soon as the windows
+    /// allow. Use `is_flushed` to learn when everything has been acknowledged.
+    pub fn flush(&mut self, now: Instant) {
+        if !self.unsent.is_empty() {
+            self.flush_requested = true;
+            self.push_unsent(now);
+        }
+    }
+
+    /// Sends buffered data as the windows allow, and then our FIN if `close`
+    /// is waiting for it. A packet smaller than the MSS waits while anything
+    /// is in flight, unless `nodelay` is set, a flush asked for it or it ends
+    /// a message. Returns why data is still buffered, if the windows are why.
+    fn push_unsent(&mut self, now: Instant) -> Option<&'static str> {
+        if !self.state.can_send() && !self.fin_pending {
+            return None; // Closed, or our FIN has already been sent
+        }
+        while !self.unsent.is_empty() {
+            let small = self.unsent.len() < self.mss;
+            if small && !self.nodelay && !self.flush_requested && !self.message_end && !self.in_flight.is_empty() {
+                return None; // Nagle: wait for an ACK or enough data for a full packet
+            }
+            let len = match self.sendable(self.unsent.len().min(self.mss), now) {
+                Ok(len) => len,
+                Err(reason) => return Some(reason),
+            };
+            let payload: Vec<u8> = self.unsent.drain(..len).collect();
+            let flags = if self.message_end && self.unsent.is_empty() { ACK | EOM } else { ACK };
+            self.transmit_data(payload, flags, now);
+        }
+        self.flush_requested = false;
+        self.message_end = false;
+        if self.fin_pending {
+            self.fin_pending = false;
+            self.send_fin(now);
+        }
+        None
+    }
+
+    /// How many of the next `len` buffered bytes may be sent now, or why
+    /// none may: the send window, the peer's receive window, the congestion
+    /// window or the pacing rate is exhausted.
+    fn sendable(&mut self, len: usize, now: Instant) -> Result<usize, &'static str> {
+        if self.in_flight.len() >= self.window_size {
+            return Err("Send window is full");
+        }
+        let usable = self.peer_window.saturating_sub(seq_distance(self.send_base(), self.sequence_number));
+        if usable == 0 {
+            if self.in_flight.is_empty() && self.persist.is_none() {
+                // No ACK is coming back to reopen the window, so ask for one.
+                self.persist = Some(PersistTimer {
+                    next_probe: now + self.rtt.rto(),
+                    probes: 0,
+                    unanswered: 0,
+                });
+            }
+            return Err("Peer's receive window is full");
+        }
+        if usable < len && !self.in_flight.is_empty() {
+            // Wait for the window to fit the whole packet rather than send slivers.
+            return Err("Peer's receive window is full");
+        }
+        let congested = self.pipe() + len > self.congestion.window() || self.in_flight.iter().any(|s| s.lost);
+        if congested && !self.in_flight.is_empty() {
+            return Err("Congestion window is full");
+        }
+        if self.next_send.is_some_and(|at| now < at) {
+            self.pacing_wait = true; // Until `handle_timeout` finds the delay over
+            return Err("Pacing the next packet");
+        }
+        Ok(len.min(usable))
+    }
+
+    /// Sends `payload` as the next data packet and tracks it until it is
+    /// acknowledged. `flags` always include `ACK`: data carries our cumulative ACK.
+    fn transmit_data(&mut self, payload: Vec<u8>, flags: u8, now: Instant) {
+        let len = payload.len();
+        let data_packet = StyxPacket {
+            version: self.version,
+            sequence_number: self.sequence_number,
+            ack_number: self.ack_number,
+            flags,
+            options: self.ack_options(),
+            payload,
+        };
+        self.transmits.push_back(data_packet.to_bytes());
+        self.ack_sent(); // Our ACK rides along with the data
+        let stamp = self.delivery_stamp(now);
+        self.in_flight.push_back(InFlightSegment {
+            packet: data_packet,
+            sent_at: now,
+            first_sent_at: now,
+            retransmissions: 0,
+            lost: false,
+            sacked: false,
+            stamp,
+        });
+        self.sequence_number = seq_add(self.sequence_number, len); // Advance by the bytes we just sent
+        self.pace(len, now);
+        self.probe_path_mtu(now); // While data flows, the peer is around to answer
+    }
+
+    /// Schedules when the next new segment may leave, the time `len` bytes
+    /// take at the congestion controller's pacing rate, if it has one.
+    fn pace(&mut self, len: usize, now: Instant) {
+        self.next_send = self.congestion.pacing_rate().filter(|&rate| rate > 0).map(|rate| {
+            let catch_up = now.checked_sub(PACING_BURST).unwrap_or(now);
+            let start = self.next_send.map_or(now, |at| at.max(catch_up));
+            start + Duration::from_secs_f64(len as f64 / rate as f64)
+        });
+    }
+
+    /// Reads payload bytes in stream order. Returns `Ok(0)` once the peer's
+    /// FIN has been reached, and `WouldBlock` while the peer may still send
+    /// more.
+    pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let packet = match self.ready.front_mut() {
+            Some(packet) if (packet.flags & FIN) == 0 => packet,
+            _ => {
+                self.reach_eof()?;
+                return Ok(0);
+            }
+        };
+        let amt = packet.payload.len().min(buf.len());
+        buf[..amt].copy_from_slice(&packet.payload[..amt]);
+        let window_before = self.recv_window();
+        let packet = self.ready.front_mut().unwrap();
+        if amt == packet.payload.len() {
+            self.ready.pop_front();
+        } else {
+            packet.payload.drain(..amt);
+            packet.sequence_number = seq_add(packet.sequence_number, amt);
+        }
+        self.announce_window(window_before);
+        Ok(amt)
+    }
+
+    /// Handles running out of data: succeeds at the peer's FIN, and
+    /// otherwise explains why nothing can be read yet.
+    fn reach_eof(&self) -> std::io::Result<()> {
+        if self.ready.front().is_some() {
+            return Ok(()); // Only the FIN is left, and it stays for later reads
+        }
+        if let Some(e) = self.error() {
+            return Err(e);
+        }
+        match self.state {
+            state if state.can_receive() => Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "No data received yet")),
+            ConnectionState::Listen | ConnectionState::SynSent | ConnectionState::SynReceived => Err(self.wrong_state("read")),
+            _ => Ok(()), // The peer has already finished sending
+        }
+    }
+
+    /// Takes the next in-order packet, payload and flags intact, or `None`
+    /// while nothing is ready. A FIN comes out here once every byte before
+    /// it has been taken; after that the peer can send nothing more.
+    pub fn recv_packet(&mut self) -> std::io::Result<Option<StyxPacket>> {
+        if let Some(e) = self.error() {
+            return Err(e);
+        }
+        if self.ready.is_empty() && !self.state.can_receive() {
+            return Err(self.wrong_state("receive"));
+        }
+        let window_before = self.recv_window();
+        let packet = self.ready.pop_front();
+        self.announce_window(window_before);
+        Ok(packet)
+    }
+
+    /// Reassembles the next message sent with `send_message`, however many
+    /// packets it took. Returns `Ok(None)` once the peer's FIN has been
+    /// reached; data the peer sent since its last message ends there and
+    /// comes out as one last message. Fails with `WouldBlock` while the message is incomplete.
+    /// Data read with `read` has no boundaries, so use one or the other.
+    pub fn recv_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let window_before = self.recv_window();
+        while let Some(packet) = self.ready.front() {
+            if (packet.flags & FIN) != 0 {
+                break;
+            }
+            let packet = self.ready.pop_front().unwrap();
+            self.partial_message.extend_from_slice(&packet.payload);
+            if (packet.flags & EOM) != 0 {
+                self.announce_window(window_before);
+                return Ok(Some(std::mem::take(&mut self.partial_message)));
+            }
+        }
+        self.announce_window(window_before);
+        if !self.partial_message.is_empty() && !self.ready.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.partial_message))); // Ended by the FIN
+        }
+        self.reach_eof()?;
+        Ok(None)
+    }
+
+    /// Starts closing our side of the connection by queueing a FIN behind any
+    /// buffered or unacknowledged data. After the peer's FIN has arrived
+    /// this finishes the passive close; otherwise it starts the active close.
+    pub fn close(&mut self, now: Instant) -> std::io::Result<()> {
+        match self.state {
+            ConnectionState::Established => {
+                self.transition(ConnectionState::FinWait1)?;
+            }
+            ConnectionState::CloseWait => {
+                self.transition(ConnectionState::LastAck)?;
+            }
+            _ => return Err(self.wrong_state("close")),
+        }
+        // Buffered data no longer waits for ACKs, and the FIN follows it.
+        self.fin_pending = true;
+        self.flush_requested = true;
+        self.push_unsent(now);
+        Ok(())
+    }
+
+    /// Queues a FIN. It occupies one sequence number and is retransmitted
+    /// like a data segment.
+    fn send_fin(&mut self, now: Instant) {
+        let fin_packet = StyxPacket {
+            version: self.version,
+            sequence_number: self.sequence_number,
+            ack_number: self.ack_number,
+            flags: FIN | ACK,
+            options: self.ack_options(),
+            payload: Vec::new(),
+        };
+        self.transmits.push_back(fin_packet.to_bytes());
+        self.ack_sent();
+        let stamp = self.delivery_stamp(now);
+        self.in_flight.push_back(InFlightSegment {
+            packet: fin_packet,
+            sent_at: now,
+            first_sent_at: now,
+            retransmissions: 0,
+            lost: false,
+            sacked: false,
+            stamp,
+        });
+        self.sequence_number = seq_add(self.sequence_number, 1);
+    }
+
+    /// Moves on once the peer's FIN has arrived in order. `reassemble` has
+    /// already acknowledged it, and it stays queued behind the data so a
+    /// reader still sees EOF.
+    fn check_peer_fin(&mut self, now: Instant) -> std::io::Result<()> {
+        if self.ready.back().is_none_or(|packet| (packet.flags & FIN) == 0) {
+            return Ok(());
+        }
+        match self.state {
+            ConnectionState::Established => {
+                self.transition(ConnectionState::CloseWait)?;
+            }
+            ConnectionState::FinWait1 => {
+                self.transition(ConnectionState::Closing)?;
+            }
+            ConnectionState::FinWait2 => {
+                self.enter_time_wait(now)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Lingers so a retransmitted FIN can still be ACK'd.
+    fn enter_time_wait(&mut self, now: Instant) -> std::io::Result<()> {
+        self.transition(ConnectionState::TimeWait)?;
+        self.close_timer = Some(now + self.time_wait_linger());
+        Ok(())
+    }
+
+    /// How long `TimeWait` lasts: long enough for the peer to retransmit its FIN.
+    fn time_wait_linger(&self) -> Duration {
+        TIME_WAIT_DURATION.max(self.rtt.rto() * 2)
+    }
+
+    /// Builds the stateless SYN-ACK a listener with SYN cookies sends in
+    /// reply to `syn_packet` from `remote`.
+    fn syn_cookie_reply(&self, remote: SocketAddr, syn_packet: &StyxPacket, negotiated_version: u8) -> Vec<u8> {
+        let client_isn = syn_packet.sequence_number;
+        let mut syn_ack_packet = StyxPacket {
+            version: MIN_PROTOCOL_VERSION,
+            sequence_number: syn_cookie(self.local_addr, remote, client_isn, negotiated_version),
+            ack_number: seq_add(client_isn, 1),
+            flags: SYN | ACK,
+            options: PacketOptions::new(),
+            payload: Vec::new(),
+        };
+        syn_ack_packet.options.set_version(negotiated_version).unwrap();
+        syn_ack_packet.options.set_window(self.recv_window()).unwrap();
+        syn_ack_packet.options.set_mss(MAX_SEGMENT_SIZE as u16).unwrap();
+        syn_ack_packet.to_bytes()
+    }
+}
+
+/// Picks the highest version both sides support from the version option of
+/// a SYN (the peer's highest) or SYN-ACK (the version the server selected).
+fn negotiate_version(handshake_packet: &StyxPacket) -> std::io::Result<u8> {
+    let offered = handshake_packet.options.version().unwrap_or(MIN_PROTOCOL_VERSION);
+    let version = offered.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "No mutually supported protocol version"));
+    }
+    Ok(version)
+}
+
+/// A window advertised by the peer, limited to what we are willing to honour.
+fn clamp_window(window: u32) -> usize {
+    (window as usize).min(MAX_RECV_BUFFER_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A SYN from a client whose highest version is `version`.
+    fn syn(version: u8) -> Vec<u8> {
+        let mut packet = StyxPacket {
+            version: MIN_PROTOCOL_VERSION,
+            sequence_number: 1000,
+            ack_number: 0,
+            flags: SYN,
+            options: PacketOptions::new(),
+            payload: Vec::new(),
+        };
+        packet.options.set_version(version).unwrap();
+        packet.to_bytes()
+    }
+
+    /// What the link between a `Pair` does to datagrams. The dice are a
+    /// seeded xorshift generator, so every run sees the same losses.
+    struct Link {
+        loss: f64,
+        reorder: f64,
+        corrupt: f64,
+        /// Datagrams larger than this are dropped silently.
+        mtu: usize,
+        state: u64,
+    }
+
+    impl Link {
+        fn perfect() -> Link {
+            Link::lossy(0.0, 0.0, 0.0)
+        }
+
+        fn lossy(loss: f64, reorder: f64, corrupt: f64) -> Link {
+            Link {
+                loss,
+                reorder,
+                corrupt,
+                mtu: MAX_DATAGRAM_SIZE,
+                state: 0x2545_F491_4F6C_DD1D,
+            }
+        }
+
+        fn next(&mut self) -> u64 {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state
+        }
+
+        fn chance(&mut self, probability: f64) -> bool {
+            ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+        }
+
+        /// Returns the datagrams that make it across, in arrival order:
+        /// some are dropped, some overtaken by the next one, some damaged.
+        fn carry(&mut self, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+            let mut arriving: Vec<Vec<u8>> = datagrams.into_iter().filter(|datagram| datagram.len() <= self.mtu && !self.chance(self.loss)).collect();
+            for i in 1..arriving.len() {
+                if self.chance(self.reorder) {
+                    arriving.swap(i - 1, i);
+                }
+            }
+            for datagram in &mut arriving {
+                if self.chance(self.corrupt) {
+                    let index = self.next() as usize % datagram.len();
+                    datagram[index] ^= 0x10;
+                }
+            }
+            arriving
+        }
+    }
+
+    /// A client and a server connection joined by a `Link`, driven on a
+    /// simulated clock.
+    struct Pair {
+        client: Connection,
+        server: Connection,
+        now: Instant,
+        link: Link,
+        /// Datagrams each side has sent, client first.
+        sent: [Vec<StyxPacket>; 2],
+    }
+
+    impl Pair {
+        /// Runs the handshake between a fresh client and server.
+        fn connect() -> Pair {
+            Pair::connect_with_isn(generate_isn(addr(2), addr(1)))
+        }
+
+        /// Runs the handshake with `client_isn` as the client's ISN.
+        fn connect_with_isn(client_isn: u32) -> Pair {
+            let now = Instant::now();
+            let client = Connection::connect_with_isn(addr(2), addr(1), RetransmitPolicy::default(), client_isn, now).unwrap();
+            let listener = Connection::listen(addr(1)).unwrap();
+            let mut pair = Pair {
+                client,
+                server: listener,
+                now,
+                link: Link::perfect(),
+                sent: [Vec::new(), Vec::new()],
+            };
+            let syn = pair.client.poll_transmit().unwrap();
+            pair.server = match pair.server.accept_datagram(addr(2), &syn, now).unwrap() {
+                ListenerAction::Accept(connection) => *connection,
+                _ => panic!("the listener did not accept the SYN"),
+            };
+            pair.settle();
+            assert_eq!(pair.client.state(), ConnectionState::Established);
+            assert_eq!(pair.server.state(), ConnectionState::Established);
+            pair
+        }
+
+        /// Delivers datagrams both ways until neither side has more to send.
+        fn settle(&mut self) {
+            loop {
+                let to_server: Vec<Vec<u8>> = std::iter::from_fn(|| self.client.poll_transmit()).collect();
+                let to_client: Vec<Vec<u8>> = std::iter::from_fn(|| self.server.poll_transmit()).collect();
+                if to_server.is_empty() && to_client.is_empty() {
+                    return;
+                }
+                self.sent[0].extend(to_server.iter().map(|bytes| StyxPacket::from_bytes(bytes).unwrap()));
+                self.sent[1].extend(to_client.iter().map(|bytes| StyxPacket::from_bytes(bytes).unwrap()));
+                for bytes in self.link.carry(to_server) {
+                    self.server.handle_datagram(&bytes, self.now).unwrap();
+                }
+                for bytes in self.link.carry(to_client) {
+                    self.client.handle_datagram(&bytes, self.now).unwrap();
+                }
+            }
+        }
+
+        /// Lets `duration` of simulated time pass, firing timers on the way.
+        fn advance(&mut self, duration: Duration) {
+            let until = self.now + duration;
+            loop {
+                self.settle();
+                let next = [self.client.poll_timeout(), self.server.poll_timeout()].into_iter().flatten().min();
+                match next {
+                    Some(at) if at <= until => self.now = self.now.max(at),
+                    _ => break,
+                }
+                self.client.handle_timeout(self.now).unwrap();
+                self.server.handle_timeout(self.now).unwrap();
+            }
+            self.now = until;
+        }
+
+        /// Sends `data` from the client to the server, which reads it as it
+        /// arrives, and returns what the server read.
+        fn transfer(&mut self, data: &[u8]) -> Vec<u8> {
+            self.transfer_with(data, |_| {})
+        }
+
+        /// `transfer`, calling `meddle` each round after the client has
+        /// written what it can and before any of it is delivered.
+        fn transfer_with(&mut self, data: &[u8], mut meddle: impl FnMut(&mut Pair)) -> Vec<u8> {
+            let deadline = self.now + Duration::from_secs(600);
+            let mut sent = 0;
+            let mut received = Vec::new();
+            let mut buf = [0; 4096];
+            while received.len() < data.len() {
+                assert!(self.now < deadline, "transfer stalled after {} bytes", received.len());
+                while sent < data.len() {
+                    match self.client.send(&data[sent..], self.now) {
+                        Ok(amt) => sent += amt,
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("send failed: {}", e),
+                    }
+                }
+                if sent == data.len() {
+                    self.client.flush(self.now);
+                }
+                meddle(self);
+                self.advance(Duration::from_millis(10));
+                loop {
+                    match self.server.read(&mut buf) {
+                        Ok(amt) if amt > 0 => received.extend_from_slice(&buf[..amt]),
+                        Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => panic!("read failed: {}", e),
+                        _ => break,
+                    }
+                }
+            }
+            received
+        }
+
+        /// Packets the client sent with all of `flags` set.
+        fn client_sent(&self, flags: u8) -> usize {
+            self.sent[0].iter().filter(|packet| (packet.flags & flags) == flags).count()
+        }
+    }
+
+    fn events(connection: &mut Connection) -> Vec<Event> {
+        std::iter::from_fn(|| connection.poll_event()).collect()
+    }
+
+    /// Takes what `connection` is sending and returns the data segments,
+    /// leaving out path MTU probes.
+    fn data_segments(connection: &mut Connection) -> Vec<StyxPacket> {
+        std::iter::from_fn(|| connection.poll_transmit())
+            .map(|bytes| StyxPacket::from_bytes(&bytes).unwrap())
+            .filter(|packet| packet.options.probe().is_none() && !packet.payload.is_empty())
+            .collect()
+    }
+
+    /// Bytes that differ from their neighbours, so misordering shows.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn transfers_data_across_the_sequence_wrap() {
+        let client_isn = u32::MAX - 20_000;
+        let mut pair = Pair::connect_with_isn(client_isn);
+        let data = pattern(300_000);
+        assert_eq!(pair.transfer(&data), data);
+
+        // The stream really went past 2^32, and every byte was acknowledged
+        // once the delayed ACK went out.
+        pair.advance(DELAYED_ACK_TIMEOUT);
+        let end = seq_add(client_isn, 1 + data.len());
+        assert!(end < client_isn);
+        assert!(pair.client.is_flushed());
+        assert_eq!(pair.server.ack_number, end);
+        assert!(pair.sent[0].iter().any(|packet| packet.sequence_number < client_isn && !packet.payload.is_empty()));
+
+        // Teardown works on the far side of the wrap too.
+        pair.client.close(pair.now).unwrap();
+        pair.settle();
+        assert_eq!(pair.server.ack_number, seq_add(end, 1));
+        assert_eq!(pair.client.state(), ConnectionState::FinWait2);
+        assert_eq!(pair.server.state(), ConnectionState::CloseWait);
+    }
+
+    #[test]
+    fn recovers_from_loss_and_reordering_across_the_wrap() {
+        let mut pair = Pair::connect_with_isn(u32::MAX - 50_000);
+        pair.link = Link::lossy(0.1, 0.2, 0.0);
+        let data = pattern(200_000);
+        assert_eq!(pair.transfer(&data), data);
+        let mut seen = std::collections::HashSet::new();
+        let retransmitted = pair.sent[0].iter().filter(|packet| !packet.payload.is_empty() && !seen.insert(packet.sequence_number)).count();
+        assert!(retransmitted > 0, "nothing was lost");
+
+        // Both FINs get through the same link.
+        pair.client.close(pair.now).unwrap();
+        pair.advance(Duration::from_secs(5));
+        let mut buf = [0; 16];
+        assert_eq!(pair.server.read(&mut buf).unwrap(), 0);
+        pair.server.close(pair.now).unwrap();
+        pair.advance(Duration::from_secs(5));
+        assert_eq!(pair.server.state(), ConnectionState::Closed);
+        assert!(matches!(pair.client.state(), ConnectionState::TimeWait | ConnectionState::Closed));
+        assert!(pair.client.error().is_none());
+    }
+
+    #[test]
+    fn drops_corrupted_datagrams_and_recovers() {
+        let mut pair = Pair::connect();
+        pair.link = Link::lossy(0.0, 0.0, 0.1);
+        let data = pattern(100_000);
+        assert_eq!(pair.transfer(&data), data);
+        assert!(pair.server.corrupted_packets() > 0);
+        assert!(pair.client.corrupted_packets() > 0);
+    }
+
+    #[test]
+    fn messages_keep_their_boundaries_over_a_lossy_link() {
+        let mut pair = Pair::connect();
+        pair.link = Link::lossy(0.05, 0.2, 0.0);
+        let messages: Vec<Vec<u8>> = [1, 5_000, 1, 70_000, 300].iter().map(|&len| pattern(len)).collect();
+        let mut received = Vec::new();
+        for message in &messages {
+            let mut sent = 0;
+            while sent < message.len() {
+                match pair.client.send_message(&message[sent..], pair.now) {
+                    Ok(amt) => sent += amt,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => pair.advance(Duration::from_millis(10)),
+                    Err(e) => panic!("send_message failed: {}", e),
+                }
+                while let Some(message) = pair.server.recv_message().ok().flatten() {
+                    received.push(message);
+                }
+            }
+        }
+        while received.len() < messages.len() {
+            pair.advance(Duration::from_millis(10));
+            while let Some(message) = pair.server.recv_message().ok().flatten() {
+                received.push(message);
+            }
+        }
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn fin_is_acknowledged_without_the_application_reading() {
+        let mut pair = Pair::connect();
+        pair.client.send(b"bye", pair.now).unwrap();
+        pair.client.close(pair.now).unwrap();
+        pair.settle();
+        assert_eq!(pair.server.state(), ConnectionState::CloseWait);
+        assert_eq!(pair.client.state(), ConnectionState::FinWait2);
+
+        // However long the server takes to read, the FIN is never resent.
+        // Stay within `FIN_WAIT_2_TIMEOUT` so the client still answers ours.
+        pair.advance(FIN_WAIT_2_TIMEOUT - Duration::from_secs(1));
+        assert_eq!(pair.client_sent(FIN), 1);
+        assert!(pair.client.error().is_none());
+        assert!(!events(&mut pair.client).contains(&Event::Aborted));
+
+        let mut buf = [0; 16];
+        assert_eq!(pair.server.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"bye");
+        assert_eq!(pair.server.read(&mut buf).unwrap(), 0);
+        assert_eq!(pair.server.read(&mut buf).unwrap(), 0);
+        pair.server.close(pair.now).unwrap();
+        pair.settle();
+        assert_eq!(pair.server.state(), ConnectionState::Closed);
+        assert_eq!(pair.client.state(), ConnectionState::TimeWait);
+    }
+
+    #[test]
+    fn retransmitted_segments_yield_no_rtt_sample() {
+        let mut pair = Pair::connect();
+        pair.server.set_quickack(true);
+
+        // An ACK that takes 100 ms to come back is a valid sample.
+        pair.client.send(b"a", pair.now).unwrap();
+        let data = pair.client.poll_transmit().unwrap();
+        pair.now += Duration::from_millis(100);
+        pair.server.handle_datagram(&data, pair.now).unwrap();
+        pair.settle();
+        let srtt = pair.client.srtt();
+        assert!(srtt.unwrap() > Duration::ZERO);
+
+        // The ACK of a retransmission may answer either transmission, so it
+        // is no sample at all (Karn's rule), though it arrives at once.
+        pair.client.send(b"b", pair.now).unwrap();
+        pair.client.poll_transmit().unwrap(); // Lost
+        pair.advance(pair.client.rto());
+        assert_eq!(pair.client.stats().retransmitted_packets, 1);
+        assert!(pair.client.is_flushed());
+        assert_eq!(pair.client.srtt(), srtt);
+    }
+
+    #[test]
+    fn abort_discards_buffered_data() {
+        let mut pair = Pair::connect();
+        pair.link = Link::lossy(1.0, 0.0, 0.0);
+        pair.client.send(b"first", pair.now).unwrap();
+        pair.client.send(b"second", pair.now).unwrap(); // Held back by Nagle
+        pair.advance(Duration::from_secs(600));
+        assert_eq!(pair.client.state(), ConnectionState::Closed);
+        assert!(pair.client.is_flushed());
+        let aborted = events(&mut pair.client).into_iter().filter(|event| *event == Event::Aborted).count();
+        assert_eq!(aborted, 1);
+
+        // Nothing leaves a closed connection, however long its driver runs.
+        let sent = pair.sent[0].len();
+        pair.advance(Duration::from_secs(600));
+        assert_eq!(pair.sent[0].len(), sent);
+        assert!(events(&mut pair.client).is_empty());
+    }
+
+    #[test]
+    fn shrinking_the_receive_buffer_mid_transfer_only_slows_the_sender() {
+        let mut pair = Pair::connect();
+        let data = pattern(1_000_000);
+        let started = pair.now;
+        let mut rounds = 0;
+        let mut shrunk_at = 0;
+        let received = pair.transfer_with(&data, |pair| {
+            rounds += 1;
+            if rounds != 10 {
+                return;
+            }
+            assert!(pair.client.pipe() > 2 * pair.client.mss(), "too little in flight to be cut off");
+            pair.server.set_recv_buffer_size(2000);
+            // What is on its way arrives only to be cut down to the new window.
+            for bytes in std::iter::from_fn(|| pair.client.poll_transmit()).collect::<Vec<_>>() {
+                pair.server.handle_datagram(&bytes, pair.now).unwrap();
+            }
+            shrunk_at = pair.sent[0].len();
+        });
+        assert_eq!(received, data);
+        assert!(pair.client.error().is_none());
+        // A 2000-byte window allows one packet per round trip, which is
+        // nothing like the minutes that timing out over and over takes.
+        assert!(pair.now - started < Duration::from_secs(30), "took {:?}", pair.now - started);
+        let data_packets = pair.sent[0][shrunk_at..].iter().filter(|packet| packet.options.probe().is_none());
+        let largest = data_packets.map(|packet| packet.payload.len()).max().unwrap();
+        assert!(largest <= 2000, "sent {} bytes into a 2000-byte window", largest);
+    }
+
+    #[test]
+    fn a_closed_window_is_probed_until_it_reopens() {
+        let mut pair = Pair::connect();
+        pair.server.set_recv_buffer_size(10_000);
+        let data = pattern(30_000);
+        let mut sent = 0;
+        for _ in 0..100 {
+            match pair.client.send(&data[sent..], pair.now) {
+                Ok(amt) => sent += amt,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => pair.advance(Duration::from_millis(10)),
+                Err(e) => panic!("send failed: {}", e),
+            }
+        }
+        assert_eq!(pair.client.peer_window, 0);
+
+        // Nobody reads for ten minutes. The probes back off and, being
+        // answered, never abort the connection. Whatever the client sent
+        // beyond the window before it learned of the smaller buffer probes
+        // a byte at a time too.
+        let before = pair.sent[0].len();
+        pair.advance(Duration::from_secs(600));
+        let probes = pair.sent[0][before..].iter().filter(|packet| packet.payload.len() == 1).count();
+        assert!((3..=20).contains(&probes), "{} probes", probes);
+        assert_eq!(pair.sent[0].len() - before, probes, "sent more than probes into a closed window");
+        assert_eq!(pair.client.state(), ConnectionState::Established);
+
+        // The server reads, but the ACK reopening the window is lost. The
+        // next probe finds out instead.
+        pair.link = Link::lossy(1.0, 0.0, 0.0);
+        let mut buf = vec![0; 10_000];
+        let mut received = Vec::new();
+        while let Ok(amt @ 1..) = pair.server.read(&mut buf) {
+            received.extend_from_slice(&buf[..amt]);
+        }
+        pair.settle();
+        pair.link = Link::perfect();
+        assert_eq!(pair.client.peer_window, 0);
+        pair.advance(MAX_PERSIST_INTERVAL);
+        assert!(pair.client.peer_window > 0);
+        let deadline = pair.now + Duration::from_secs(60);
+        while received.len() < data.len() {
+            assert!(pair.now < deadline, "stalled after {} bytes", received.len());
+            match pair.client.send(&data[sent..], pair.now) {
+                Ok(amt) => sent += amt,
+                Err(_) => pair.client.flush(pair.now),
+            }
+            pair.advance(Duration::from_millis(10));
+            while let Ok(amt @ 1..) = pair.server.read(&mut buf) {
+                received.extend_from_slice(&buf[..amt]);
+            }
+        }
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn only_the_holes_the_peer_did_not_sack_are_retransmitted() {
+        let mut pair = Pair::connect();
+        let mss = pair.client.mss();
+        let data = pattern(8 * mss);
+        let mut sent = 0;
+        while sent < data.len() {
+            sent += pair.client.send(&data[sent..], pair.now).unwrap();
+        }
+        let segments: Vec<StyxPacket> = std::iter::from_fn(|| pair.client.poll_transmit())
+            .map(|bytes| StyxPacket::from_bytes(&bytes).unwrap())
+            .filter(|packet| packet.options.probe().is_none())
+            .collect();
+        assert_eq!(segments.len(), 8);
+
+        // The second and fourth segments are lost; the rest arrive and are SACKed.
+        let mut acks = Vec::new();
+        for (index, segment) in segments.iter().enumerate() {
+            if index != 1 && index != 3 {
+                pair.server.handle_datagram(&segment.to_bytes(), pair.now).unwrap();
+                acks.extend(std::iter::from_fn(|| pair.server.poll_transmit()));
+            }
+        }
+        let acks: Vec<StyxPacket> = acks.iter().map(|bytes| StyxPacket::from_bytes(bytes).unwrap()).collect();
+        assert_eq!(acks.last().unwrap().options.sack_blocks().len(), 2);
+        for ack in &acks {
+            pair.client.handle_datagram(&ack.to_bytes(), pair.now).unwrap();
+        }
+
+        // Well before any timer, exactly the two holes are resent.
+        let resent: Vec<u32> = std::iter::from_fn(|| pair.client.poll_transmit())
+            .map(|bytes| StyxPacket::from_bytes(&bytes).unwrap())
+            .filter(|packet| packet.options.probe().is_none() && !packet.payload.is_empty())
+            .map(|packet| packet.sequence_number)
+            .collect();
+        assert_eq!(resent, [segments[1].sequence_number, segments[3].sequence_number]);
+        assert_eq!(pair.client.retransmitted_packets, 2);
+    }
+
+    #[test]
+    fn every_second_segment_is_acknowledged_and_a_lone_one_within_40_ms() {
+        let mut pair = Pair::connect();
+        let data = pattern(5 * pair.client.mss());
+        let mut sent = 0;
+        while sent < data.len() {
+            sent += pair.client.send(&data[sent..], pair.now).unwrap();
+        }
+        let segments = data_segments(&mut pair.client);
+        assert_eq!(segments.len(), 5);
+
+        let mut acks = Vec::new();
+        for segment in &segments {
+            pair.server.handle_datagram(&segment.to_bytes(), pair.now).unwrap();
+            acks.push(std::iter::from_fn(|| pair.server.poll_transmit()).count());
+        }
+        assert_eq!(acks, [0, 1, 0, 1, 0]);
+
+        // The fifth segment has no partner, so the timer acknowledges it.
+        assert_eq!(pair.server.poll_timeout(), Some(pair.now + DELAYED_ACK_TIMEOUT));
+        pair.server.handle_timeout(pair.now + DELAYED_ACK_TIMEOUT - Duration::from_millis(1)).unwrap();
+        assert!(pair.server.poll_transmit().is_none());
+        pair.server.handle_timeout(pair.now + DELAYED_ACK_TIMEOUT).unwrap();
+        let ack = StyxPacket::from_bytes(&pair.server.poll_transmit().unwrap()).unwrap();
+        assert_eq!(ack.ack_number, seq_add(segments[4].sequence_number, segments[4].payload.len()));
+        assert!(pair.server.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn small_writes_wait_for_the_ack_unless_nodelay_is_set() {
+        let mut pair = Pair::connect();
+        assert_eq!(pair.client.send(b"first", pair.now).unwrap(), 5);
+        let first = data_segments(&mut pair.client);
+        assert_eq!(first.len(), 1, "nothing in flight, so the first write goes at once");
+
+        // Later small writes are held while the first is unacknowledged...
+        for _ in 0..4 {
+            assert_eq!(pair.client.send(b"small", pair.now).unwrap(), 5);
+        }
+        assert!(data_segments(&mut pair.client).is_empty());
+
+        // ...and leave together once its ACK arrives.
+        pair.server.handle_datagram(&first[0].to_bytes(), pair.now).unwrap();
+        pair.now += DELAYED_ACK_TIMEOUT;
+        pair.server.handle_timeout(pair.now).unwrap();
+        for ack in std::iter::from_fn(|| pair.server.poll_transmit()).collect::<Vec<_>>() {
+            pair.client.handle_datagram(&ack, pair.now).unwrap();
+        }
+        let coalesced = data_segments(&mut pair.client);
+        assert_eq!(coalesced.len(), 1);
+        assert_eq!(coalesced[0].payload, b"smallsmallsmallsmall");
+
+        let mut pair = Pair::connect();
+        pair.client.set_nodelay(true, pair.now);
+        for _ in 0..4 {
+            assert_eq!(pair.client.send(b"small", pair.now).unwrap(), 5);
+        }
+        assert_eq!(data_segments(&mut pair.client).len(), 4);
+    }
+
+    #[test]
+    fn segments_never_exceed_the_mss_the_peer_announced() {
+        let now = Instant::now();
+        let listener = Connection::listen(addr(1)).unwrap();
+        for (announced, expected) in [(None, MAX_PAYLOAD_SIZE), (Some(600), 600)] {
+            let mut packet = StyxPacket::from_bytes(&syn(PROTOCOL_VERSION)).unwrap();
+            if let Some(mss) = announced {
+                packet.options.set_mss(mss).unwrap();
+            }
+            let mut server = match listener.accept_datagram(addr(2), &packet.to_bytes(), now).unwrap() {
+                ListenerAction::Accept(connection) => *connection,
+                _ => panic!("the listener did not accept the SYN"),
+            };
+            assert_eq!(server.mss(), expected);
+            assert!(server.pmtu.next_probe().is_none(), "nothing above the base size to look for");
+        }
+
+        // Two Styx peers both take jumbo segments, so the path decides.
+        let mut pair = Pair::connect();
+        assert_eq!(pair.client.mss(), MAX_PAYLOAD_SIZE);
+        let data = pattern(200_000);
+        assert_eq!(pair.transfer(&data), data);
+        assert_eq!(pair.client.mss(), 8972 - DATA_OVERHEAD);
+        assert_eq!(pair.client.stats().path_mtu, 8972);
+    }
+
+    #[test]
+    fn large_segments_lost_in_a_black_hole_fall_back_to_the_base_size() {
+        let mut pair = Pair::connect();
+        let data = pattern(200_000);
+        assert_eq!(pair.transfer(&data), data);
+        assert!(pair.client.mss() > MAX_PAYLOAD_SIZE);
+
+        // The path MTU shrinks: three timeouts in a row later the sender
+        // gives up on large packets, and the data still gets through.
+        pair.link.mtu = BASE_DATAGRAM_SIZE;
+        let started = pair.now;
+        assert_eq!(pair.transfer(&data), data);
+        assert!(pair.client.error().is_none());
+        assert_eq!(pair.client.mss(), MAX_PAYLOAD_SIZE);
+        assert_eq!(pair.client.stats().path_mtu, BASE_DATAGRAM_SIZE);
+        assert!(pair.now - started < Duration::from_secs(10), "took {:?}", pair.now - started);
+    }
+
+    #[test]
+    fn windows_stay_well_under_half_the_sequence_space() {
+        let mut pair = Pair::connect();
+        pair.server.set_recv_buffer_size(usize::MAX);
+        assert_eq!(pair.server.recv_buffer_size(), MAX_RECV_BUFFER_SIZE);
+        pair.client.send(b"x", pair.now).unwrap();
+        pair.advance(DELAYED_ACK_TIMEOUT);
+        // The unread byte still occupies the buffer.
+        assert_eq!(pair.sent[1].last().unwrap().options.window(), Some(MAX_RECV_BUFFER_SIZE as u32 - 1));
+        assert_eq!(pair.client.peer_window, MAX_RECV_BUFFER_SIZE - 1);
+
+        // A peer announcing more than that is held to the same limit.
+        let mut ack = StyxPacket::from_bytes(&pair.sent[1].last().unwrap().to_bytes()).unwrap();
+        ack.options.set_window(u32::MAX).unwrap();
+        pair.client.handle_datagram(&ack.to_bytes(), pair.now).unwrap();
+        assert_eq!(pair.client.peer_window, MAX_RECV_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn data_moves_only_in_states_that_allow_it() {
+        let now = Instant::now();
+        let mut client = Connection::connect(addr(2), addr(1), RetransmitPolicy::default(), now).unwrap();
+        assert_eq!(client.send(b"early", now).unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+        assert_eq!(client.read(&mut [0; 8]).unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+        assert_eq!(client.recv_packet().unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+
+        let mut pair = Pair::connect();
+        pair.client.send(b"last words", pair.now).unwrap();
+        pair.client.close(pair.now).unwrap();
+        assert_eq!(pair.client.state(), ConnectionState::FinWait1);
+        assert_eq!(pair.client.send(b"more", pair.now).unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+        pair.settle();
+
+        // The server has the FIN, but what came before it can still be taken.
+        assert_eq!(pair.server.state(), ConnectionState::CloseWait);
+        assert_eq!(pair.server.recv_packet().unwrap().unwrap().payload, b"last words");
+        assert_ne!(pair.server.recv_packet().unwrap().unwrap().flags & FIN, 0);
+        assert_eq!(pair.server.recv_packet().unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+        // Our own direction stays open until we close it.
+        assert_eq!(pair.server.send(b"reply", pair.now).unwrap(), 5);
+        pair.server.close(pair.now).unwrap();
+        assert_eq!(pair.server.send(b"more", pair.now).unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+    }
+
+    /// Runs a cookie handshake up to the client's final ACK, which is
+    /// returned undelivered along with the pair.
+    fn cookie_handshake() -> (Pair, Connection, Vec<u8>) {
+        let now = Instant::now();
+        let mut listener = Connection::listen(addr(1)).unwrap();
+        listener.set_syn_cookies(true);
+        let mut client = Connection::connect_with_isn(addr(2), addr(1), RetransmitPolicy::default(), 1000, now).unwrap();
+        let syn = client.poll_transmit().unwrap();
+        let syn_ack = match listener.accept_datagram(addr(2), &syn, now).unwrap() {
+            ListenerAction::Reply(bytes) => bytes,
+            _ => panic!("the listener did not answer with a cookie"),
+        };
+        client.handle_datagram(&syn_ack, now).unwrap();
+        assert_eq!(client.state(), ConnectionState::Established);
+        let ack = client.poll_transmit().unwrap();
+        let pair = Pair {
+            client,
+            server: Connection::listen(addr(1)).unwrap(),
+            now,
+            link: Link::perfect(),
+            sent: [Vec::new(), Vec::new()],
+        };
+        (pair, listener, ack)
+    }
+
+    #[test]
+    fn cookie_handshake_completes() {
+        let (mut pair, listener, ack) = cookie_handshake();
+        pair.server = match listener.accept_datagram(addr(2), &ack, pair.now).unwrap() {
+            ListenerAction::Accept(connection) => *connection,
+            _ => panic!("the listener did not accept a genuine cookie"),
+        };
+        assert_eq!(pair.server.state(), ConnectionState::Established);
+        assert_eq!(events(&mut pair.server), [Event::Connected]);
+        assert_eq!(pair.server.mss(), pair.client.mss());
+        let data = pattern(50_000);
+        assert_eq!(pair.transfer(&data), data);
+    }
+
+    #[test]
+    fn data_completes_a_cookie_handshake_whose_ack_was_lost() {
+        let (mut pair, listener, _) = cookie_handshake();
+        pair.client.send(b"hello", pair.now).unwrap();
+        let data = pair.client.poll_transmit().unwrap();
+        pair.server = match listener.accept_datagram(addr(2), &data, pair.now).unwrap() {
+            ListenerAction::Accept(connection) => *connection,
+            _ => panic!("the listener did not accept data echoing a cookie"),
+        };
+        let mut buf = [0; 16];
+        assert_eq!(pair.server.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn forged_and_stale_cookies_are_ignored() {
+        let (pair, listener, ack) = cookie_handshake();
+        let cookie = seq_sub(StyxPacket::from_bytes(&ack).unwrap().ack_number, 1);
+        let stale = crate::isn::stale_syn_cookie(addr(1), addr(2), 1000, pair.client.version());
+        for (from, cookie) in [(addr(2), cookie ^ 1), (addr(2), stale), (addr(3), cookie)] {
+            let mut forged = StyxPacket::from_bytes(&ack).unwrap();
+            forged.ack_number = seq_add(cookie, 1);
+            let action = listener.accept_datagram(from, &forged.to_bytes(), pair.now).unwrap();
+            assert!(matches!(action, ListenerAction::Ignore), "accepted cookie {:#x} from {}", cookie, from);
+        }
+    }
+
+    #[test]
+    fn listener_ignores_syn_without_common_version() {
+        let now = Instant::now();
+        for syn_cookies in [false, true] {
+            let mut listener = Connection::listen(addr(1)).unwrap();
+            listener.set_syn_cookies(syn_cookies);
+            let action = listener.accept_datagram(addr(2), &syn(MIN_PROTOCOL_VERSION - 1), now).unwrap();
+            assert!(matches!(action, ListenerAction::Ignore));
+            // The listener keeps accepting compatible clients afterwards.
+            let action = listener.accept_datagram(addr(2), &syn(PROTOCOL_VERSION), now).unwrap();
+            assert!(!matches!(action, ListenerAction::Ignore));
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// src/isn.rs
+
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::packet::MIN_PROTOCOL_VERSION;
+
+/// Length of one tick of the SYN cookie clock.
+const COOKIE_TICK: Duration = Duration::from_secs(64);
+/// Ticks a SYN cookie stays valid for, counting the one it was issued in.
+const COOKIE_LIFETIME: u32 = 2;
+
+/// The keyed PRF behind ISNs and SYN cookies: SipHash-2-4 under a 128-bit
+/// secret drawn once per process from the operating system's RNG. Unlike
+/// std's `RandomState`, whose algorithm may change, it is pinned here.
+fn secret() -> SipHasher24 {
+    static SECRET: OnceLock<SipHasher24> = OnceLock::new();
+    *SECRET.get_or_init(|| {
+        let mut key = [0; 16];
+        getrandom::fill(&mut key).expect("The operating system's random number generator failed");
+        let (key0, key1) = key.split_at(8);
+        SipHasher24::new_with_keys(u64::from_le_bytes(key0.try_into().unwrap()), u64::from_le_bytes(key1.try_into().unwrap()))
+    })
+}
+
+/// Feeds `addr` to the PRF with a fixed encoding: a family byte, the
+/// address bytes and the port.
+fn write_addr(hasher: &mut SipHasher24, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            hasher.write_u8(4);
+            hasher.write(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            hasher.write_u8(6);
+            hasher.write(&ip.octets());
+        }
+    }
+    hasher.write(&addr.port().to_be_bytes());
+}
+
+/// Time since the ISN clocks started.
+fn uptime() -> Duration {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed()
+}
+
+/// Ticks of the 4 microsecond ISN clock since the process started (RFC 6528's `M`).
+fn clock() -> u32 {
+    (uptime().as_micros() / 4) as u32 // Wraps roughly every 4.7 hours, as intended
+}
+
+/// Current tick of the SYN cookie clock, in the 5 bits a cookie has room for.
+fn cookie_clock() -> u32 {
+    (uptime().as_secs() / COOKIE_TICK.as_secs()) as u32 & 0x1F
+}
+
+/// Keyed hash of the connection's 4-tuple (RFC 6528's `F`).
+fn tuple_hash(local: SocketAddr, remote: SocketAddr) -> u32 {
+    let mut hasher = secret();
+    write_addr(&mut hasher, local);
+    write_addr(&mut hasher, remote);
+    hasher.finish() as u32
+}
+
+/// Picks an initial sequence number for the connection between `local` and
+/// `remote`, following RFC 6528: `ISN = M + F(local, remote, secret)`.
+///
+/// The keyed hash spreads ISNs over the whole 32-bit space so an off-path
+/// attacker cannot guess them, while the clock keeps successive ISNs of the
+/// same 4-tuple moving forward so old segments do not fall into a new
+/// incarnation's window.
+pub(crate) fn generate_isn(local: SocketAddr, remote: SocketAddr) -> u32 {
+    clock().wrapping_add(tuple_hash(local, remote))
+}
+
+/// Builds the server ISN for a stateless SYN-ACK. The cookie carries
+/// everything needed to create the connection once the client's ACK returns:
+///
+/// - bits 31..27: the cookie clock tick it was issued in,
+/// - bits 26..24: the negotiated version, relative to `MIN_PROTOCOL_VERSION`,
+/// - bits 23..0: a keyed hash binding the above to the 4-tuple and client ISN.
+pub(crate) fn syn_cookie(local: SocketAddr, remote: SocketAddr, client_isn: u32, version: u8) -> u32 {
+    cookie_at(local, remote, client_isn, version, cookie_clock())
+}
+
+/// Checks a cookie echoed back in the ACK that completes a stateless
+/// handshake, returning the version it encodes if it is genuine and recent.
+pub(crate) fn check_syn_cookie(local: SocketAddr, remote: SocketAddr, client_isn: u32, cookie: u32) -> Option<u8> {
+    let tick = cookie >> 27;
+    let version = MIN_PROTOCOL_VERSION.wrapping_add(((cookie >> 24) & 0x7) as u8);
+    let age = cookie_clock().wrapping_sub(tick) & 0x1F;
+    let genuine = age < COOKIE_LIFETIME && cookie_at(local, remote, client_isn, version, tick) == cookie;
+    genuine.then_some(version)
+}
+
+/// A cookie that was genuine once but has since expired.
+#[cfg(test)]
+pub(crate) fn stale_syn_cookie(local: SocketAddr, remote: SocketAddr, client_isn: u32, version: u8) -> u32 {
+    cookie_at(local, remote, client_isn, version, cookie_clock().wrapping_sub(COOKIE_LIFETIME) & 0x1F)
+}
+
+fn cookie_at(local: SocketAddr, remote: SocketAddr, client_isn: u32, version: u8, tick: u32) -> u32 {
+    let version_bits = (version.wrapping_sub(MIN_PROTOCOL_VERSION) & 0x7) as u32;
+    let mut hasher = secret();
+    write_addr(&mut hasher, local);
+    write_addr(&mut hasher, remote);
+    hasher.write(&client_isn.to_be_bytes());
+    hasher.write_u8(version);
+    hasher.write(&tick.to_be_bytes());
+    let mac = hasher.finish() as u32 & 0xFF_FFFF;
+    tick << 27 | version_bits << 24 | mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PROTOCOL_VERSION;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn isns_depend_on_the_four_tuple() {
+        let a = tuple_hash(addr(1), addr(2));
+        assert_eq!(a, tuple_hash(addr(1), addr(2)));
+        assert_ne!(a, tuple_hash(addr(2), addr(1)));
+        assert_ne!(a, tuple_hash(addr(1), addr(3)));
+        assert_ne!(a, tuple_hash(addr(1), SocketAddr::from(([127, 0, 0, 2], 2))));
+    }
+
+    #[test]
+    fn syn_cookies_validate_only_their_own_connection() {
+        let cookie = syn_cookie(addr(1), addr(2), 1000, PROTOCOL_VERSION);
+        assert_eq!(check_syn_cookie(addr(1), addr(2), 1000, cookie), Some(PROTOCOL_VERSION));
+        assert_eq!(check_syn_cookie(addr(1), addr(2), 1001, cookie), None);
+        assert_eq!(check_syn_cookie(addr(1), addr(3), 1000, cookie), None);
+        assert_eq!(check_syn_cookie(addr(1), addr(2), 1000, cookie ^ 1), None);
+    }
+
+    #[test]
+    fn syn_cookies_expire() {
+        let stale = stale_syn_cookie(addr(1), addr(2), 1000, PROTOCOL_VERSION);
+        assert_eq!(check_syn_cookie(addr(1), addr(2), 1000, stale), None);
+    }
+}
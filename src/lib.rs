@@ -1,7 +1,20 @@
 // src/lib.rs
 
-/// This file makes the 'packet' module available as a library.
-/// Binaries like 'client' and 'server' can then use it.
+// The crate keeps its original name so `use Styx::...` continues to work.
+#![allow(non_snake_case)]
+
+// This file makes the 'packet' module available as a library.
+// Binaries like 'client' and 'server' can then use it.
+#[cfg(feature = "tokio")]
+pub mod async_socket;
+pub mod congestion;
+pub mod connection;
+mod isn;
+pub mod options;
 pub mod packet;
+pub mod pmtu;
+pub mod rtt;
+pub mod seq;
 pub mod state;
 pub mod styx_socket;
+mod transport;
@@ -0,0 +1,273 @@
+// src/options.rs
+
+// Option kinds carried in the StyxPacket header's type-length-value area.
+pub const OPT_PADDING: u8 = 0; // Single byte with no length, used for alignment
+pub const OPT_VERSION: u8 = 1; // Protocol version offered in a SYN / selected in a SYN-ACK
+pub const OPT_WINDOW: u8 = 2; // Receive window: bytes the sender may still send beyond the ACK
+pub const OPT_SACK: u8 = 3; // Selective ACK: ranges received beyond the cumulative ACK
+pub const OPT_MSS: u8 = 4; // Largest payload the sender accepts in one data packet, in a SYN / SYN-ACK
+pub const OPT_PROBE: u8 = 5; // Path MTU probe token, echoed in the ACK that answers the probe
+
+/// Bytes the receive window option occupies on the wire (kind, length and a u32).
+pub const WINDOW_OPTION_SIZE: usize = 2 + 4;
+
+/// Most SACK blocks one ACK reports.
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+/// Largest options area the one-byte header-length field can describe.
+pub const MAX_OPTIONS_SIZE: usize = u8::MAX as usize - crate::packet::HEADER_SIZE;
+
+/// Type-length-value options that follow the fixed StyxPacket header.
+///
+/// Every option is encoded as a kind byte, a length byte and `length` value
+/// bytes. Options with a kind this implementation does not know are kept as
+/// raw bytes so they survive a parse/serialize round trip, but are otherwise
+/// skipped by the typed accessors.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PacketOptions {
+    entries: Vec<(u8, Vec<u8>)>,
+}
+
+impl PacketOptions {
+    /// Creates an empty options area.
+    pub fn new() -> Self {
+        PacketOptions::default()
+    }
+
+    /// Returns true if no options are present.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the raw value of the first option of `kind`, if present.
+    pub fn get(&self, kind: u8) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Sets the option of `kind` to `value`, replacing any previous value.
+    /// Fails if the options area would no longer fit in the header.
+    pub fn set(&mut self, kind: u8, value: &[u8]) -> Result<(), &'static str> {
+        if kind == OPT_PADDING {
+            return Err("Padding cannot carry a value");
+        }
+        if value.len() > u8::MAX as usize {
+            return Err("Option value too long");
+        }
+        let replaced = self.get(kind).map_or(0, |old| 2 + old.len());
+        if self.encoded_len() - replaced + 2 + value.len() > MAX_OPTIONS_SIZE {
+            return Err("Options do not fit in the header");
+        }
+        self.remove(kind);
+        self.entries.push((kind, value.to_vec()));
+        Ok(())
+    }
+
+    /// Removes every option of `kind`.
+    pub fn remove(&mut self, kind: u8) {
+        self.entries.retain(|(k, _)| *k != kind);
+    }
+
+    /// Protocol version carried by a SYN or SYN-ACK.
+    pub fn version(&self) -> Option<u8> {
+        match self.get(OPT_VERSION) {
+            Some([version]) => Some(*version),
+            _ => None,
+        }
+    }
+
+    /// Sets the protocol version offered in a SYN or selected in a SYN-ACK.
+    pub fn set_version(&mut self, version: u8) -> Result<(), &'static str> {
+        self.set(OPT_VERSION, &[version])
+    }
+
+    /// Receive window advertised alongside the packet's ACK, in bytes.
+    pub fn window(&self) -> Option<u32> {
+        match self.get(OPT_WINDOW) {
+            Some(&[a, b, c, d]) => Some(u32::from_be_bytes([a, b, c, d])),
+            _ => None,
+        }
+    }
+
+    /// Advertises how many bytes beyond the ACK we are able to buffer.
+    pub fn set_window(&mut self, window: u32) -> Result<(), &'static str> {
+        self.set(OPT_WINDOW, &window.to_be_bytes())
+    }
+
+    /// Largest payload the sender of a SYN or SYN-ACK accepts in one data packet.
+    pub fn mss(&self) -> Option<u16> {
+        match self.get(OPT_MSS) {
+            Some(&[a, b]) => Some(u16::from_be_bytes([a, b])),
+            _ => None,
+        }
+    }
+
+    /// Announces the largest payload we accept in one data packet.
+    pub fn set_mss(&mut self, mss: u16) -> Result<(), &'static str> {
+        self.set(OPT_MSS, &mss.to_be_bytes())
+    }
+
+    /// Token of a path MTU probe, or of the probe an ACK answers.
+    pub fn probe(&self) -> Option<u32> {
+        match self.get(OPT_PROBE) {
+            Some(&[a, b, c, d]) => Some(u32::from_be_bytes([a, b, c, d])),
+            _ => None,
+        }
+    }
+
+    /// Marks a packet as path MTU probe `token`, or as the answer to it.
+    pub fn set_probe(&mut self, token: u32) -> Result<(), &'static str> {
+        self.set(OPT_PROBE, &token.to_be_bytes())
+    }
+
+    /// Byte ranges received beyond the cumulative ACK, each as the start and
+    /// one past the end of a block. Empty if none are reported.
+    pub fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        match self.get(OPT_SACK) {
+            Some(value) if value.len() % 8 == 0 => value
+                .chunks_exact(8)
+                .map(|block| {
+                    let start = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                    let end = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                    (start, end)
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Reports up to `MAX_SACK_BLOCKS` ranges received beyond the ACK, the
+    /// most recently changed one first (RFC 2018).
+    pub fn set_sack_blocks(&mut self, blocks: &[(u32, u32)]) -> Result<(), &'static str> {
+        if blocks.len() > MAX_SACK_BLOCKS {
+            return Err("Too many SACK blocks");
+        }
+        let value: Vec<u8> = blocks.iter().flat_map(|&(start, end)| start.to_be_bytes().into_iter().chain(end.to_be_bytes())).collect();
+        self.set(OPT_SACK, &value)
+    }
+
+    /// Number of bytes the options occupy on the wire.
+    pub fn encoded_len(&self) -> usize {
+        self.entries.iter().map(|(_, value)| 2 + value.len()).sum()
+    }
+
+    /// Appends the encoded options to `bytes`.
+    pub fn write_to(&self, bytes: &mut Vec<u8>) {
+        for (kind, value) in &self.entries {
+            bytes.push(*kind);
+            bytes.push(value.len() as u8);
+            bytes.extend_from_slice(value);
+        }
+    }
+
+    /// Parses an options area, skipping padding bytes.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut options = PacketOptions::new();
+        while let Some(&kind) = bytes.first() {
+            if kind == OPT_PADDING {
+                bytes = &bytes[1..];
+                continue;
+            }
+            if bytes.len() < 2 || bytes.len() < 2 + bytes[1] as usize {
+                return Err("Truncated header option");
+            }
+            let len = bytes[1] as usize;
+            options.entries.push((kind, bytes[2..2 + len].to_vec()));
+            bytes = &bytes[2 + len..];
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{StyxPacket, ACK, PROTOCOL_VERSION};
+
+    #[test]
+    fn typed_options_round_trip() {
+        let mut options = PacketOptions::new();
+        options.set_version(PROTOCOL_VERSION).unwrap();
+        options.set_window(65_536).unwrap();
+        options.set_mss(1448).unwrap();
+        options.set_probe(7).unwrap();
+        options.set_sack_blocks(&[(10, 20), (u32::MAX - 5, 3)]).unwrap();
+
+        let mut bytes = Vec::new();
+        options.write_to(&mut bytes);
+        assert_eq!(bytes.len(), options.encoded_len());
+        let parsed = PacketOptions::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, options);
+        assert_eq!(parsed.version(), Some(PROTOCOL_VERSION));
+        assert_eq!(parsed.window(), Some(65_536));
+        assert_eq!(parsed.mss(), Some(1448));
+        assert_eq!(parsed.probe(), Some(7));
+        assert_eq!(parsed.sack_blocks(), vec![(10, 20), (u32::MAX - 5, 3)]);
+    }
+
+    #[test]
+    fn set_replaces_the_previous_value() {
+        let mut options = PacketOptions::new();
+        options.set_window(1).unwrap();
+        options.set_window(2).unwrap();
+        assert_eq!(options.window(), Some(2));
+        assert_eq!(options.encoded_len(), WINDOW_OPTION_SIZE);
+    }
+
+    #[test]
+    fn unknown_options_are_skipped_but_kept() {
+        // An unknown kind 200 with three bytes, padding, then a window.
+        let bytes = [200, 3, 1, 2, 3, OPT_PADDING, OPT_PADDING, OPT_WINDOW, 4, 0, 0, 1, 0];
+        let options = PacketOptions::from_bytes(&bytes).unwrap();
+        assert_eq!(options.window(), Some(256));
+        assert_eq!(options.get(200), Some(&[1, 2, 3][..]));
+        assert_eq!(options.version(), None);
+
+        // Padding is dropped, the unknown option survives re-encoding.
+        let mut encoded = Vec::new();
+        options.write_to(&mut encoded);
+        assert_eq!(encoded, [200, 3, 1, 2, 3, OPT_WINDOW, 4, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn malformed_values_are_ignored_by_accessors() {
+        let options = PacketOptions::from_bytes(&[OPT_WINDOW, 2, 0, 1, OPT_SACK, 3, 0, 0, 0]).unwrap();
+        assert_eq!(options.window(), None);
+        assert!(options.sack_blocks().is_empty());
+    }
+
+    #[test]
+    fn truncated_options_are_rejected() {
+        assert_eq!(PacketOptions::from_bytes(&[OPT_WINDOW]), Err("Truncated header option"));
+        assert_eq!(PacketOptions::from_bytes(&[OPT_WINDOW, 4, 0, 0]), Err("Truncated header option"));
+    }
+
+    #[test]
+    fn options_must_fit_in_the_header() {
+        let mut options = PacketOptions::new();
+        assert_eq!(options.set(OPT_PADDING, &[1]), Err("Padding cannot carry a value"));
+        assert_eq!(options.set(200, &[0; 256]), Err("Option value too long"));
+        options.set(200, &[0; MAX_OPTIONS_SIZE - 2]).unwrap();
+        assert_eq!(options.set(201, &[]), Err("Options do not fit in the header"));
+        assert_eq!(options.set_sack_blocks(&[(0, 1); MAX_SACK_BLOCKS + 1]), Err("Too many SACK blocks"));
+    }
+
+    #[test]
+    fn packets_carry_options_between_header_and_payload() {
+        let mut packet = StyxPacket {
+            version: PROTOCOL_VERSION,
+            sequence_number: 1,
+            ack_number: 2,
+            flags: ACK,
+            options: PacketOptions::new(),
+            payload: b"payload".to_vec(),
+        };
+        packet.options.set(200, b"future field").unwrap();
+        packet.options.set_window(4096).unwrap();
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[3] as usize, crate::packet::HEADER_SIZE + packet.options.encoded_len());
+        assert_eq!(StyxPacket::from_bytes(&bytes), Ok(packet));
+    }
+}
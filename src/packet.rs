@@ -1,52 +1,180 @@
 // src/packet.rs
 
+use crate::options::PacketOptions;
+
 // Flags for the StyxPacket header. They can be combined using bitwise OR.
 pub const SYN: u8 = 1 << 0; // Synchronize sequence numbers
 pub const ACK: u8 = 1 << 1; // Acknowledge
 pub const FIN: u8 = 1 << 2; // No more data from sender
+pub const EOM: u8 = 1 << 3; // Last packet of a message sent with `send_message`
+
+/// Magic bytes ("SX") that open every Styx datagram.
+pub const MAGIC: [u8; 2] = *b"SX";
+/// Highest wire-format version this implementation speaks.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Lowest wire-format version this implementation still accepts. Handshake
+/// packets are always encoded with it so that any peer can parse them.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
 
 /// Represents a single data packet in the Styx protocol.
 #[derive(Debug, PartialEq)]
 pub struct StyxPacket {
-    /// Sequence number of the packet.
+    /// Wire-format version the packet is encoded with.
+    pub version: u8,
+    /// Byte offset of the first payload byte in the sender's stream.
+    /// SYN and FIN each occupy one sequence number of their own.
     pub sequence_number: u32,
-    /// Sequence number of the packet being acknowledged.
+    /// Next byte offset the sender of this packet expects to receive.
     pub ack_number: u32,
-    /// Combination of flags (SYN, ACK, FIN).
+    /// Combination of flags (SYN, ACK, FIN, EOM).
     pub flags: u8,
+    /// Type-length-value options carried between the fixed header and the payload.
+    pub options: PacketOptions,
     /// The data payload of the packet.
     pub payload: Vec<u8>,
 }
 
-const HEADER_SIZE: usize = 9; // 4 (seq) + 4 (ack) + 1 (flags)
+/// Size of the fixed header. The header-length field also counts the
+/// options area that follows it.
+pub const HEADER_SIZE: usize = 17; // 2 (magic) + 1 (version) + 1 (header length) + 4 (seq) + 4 (ack) + 1 (flags) + 4 (checksum)
+const CHECKSUM_OFFSET: usize = 13;
+
+/// CRC32C (Castagnoli) lookup table, reflected polynomial 0x82F63B78.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC32C of `bytes`, treating the checksum field as zero.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let byte = if (CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4).contains(&i) { 0 } else { byte };
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
 
 impl StyxPacket {
-    /// Serializes the StyxPacket into a byte vector.
+    /// Serializes the StyxPacket into a byte vector, filling in the
+    /// CRC32C checksum over the header and payload.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        let header_len = HEADER_SIZE + self.options.encoded_len();
+        let mut bytes = Vec::with_capacity(header_len + self.payload.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(self.version);
+        bytes.push(header_len as u8);
         bytes.extend_from_slice(&self.sequence_number.to_be_bytes());
         bytes.extend_from_slice(&self.ack_number.to_be_bytes());
         bytes.push(self.flags);
+        bytes.extend_from_slice(&[0; 4]); // Checksum placeholder
+        self.options.write_to(&mut bytes);
         bytes.extend_from_slice(&self.payload);
+        let crc = checksum(&bytes);
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&crc.to_be_bytes());
         bytes
     }
 
-    /// Deserializes a byte slice into a StyxPacket.
+    /// Deserializes a byte slice into a StyxPacket, rejecting datagrams that
+    /// are not Styx packets, use an unsupported version, or fail the checksum.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
         if bytes.len() < HEADER_SIZE {
             return Err("Packet too small for header");
         }
+        if bytes[0..2] != MAGIC {
+            return Err("Not a Styx packet (bad magic)");
+        }
+        let version = bytes[2];
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err("Unsupported protocol version");
+        }
+        let header_len = bytes[3] as usize;
+        if header_len < HEADER_SIZE || header_len > bytes.len() {
+            return Err("Invalid header length");
+        }
+        let expected = u32::from_be_bytes(bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].try_into().unwrap());
+        if checksum(bytes) != expected {
+            return Err("Packet checksum mismatch");
+        }
 
-        let sequence_number = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
-        let ack_number = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
-        let flags = bytes[8];
-        let payload = bytes[HEADER_SIZE..].to_vec();
+        let sequence_number = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        let ack_number = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+        let flags = bytes[12];
+        let options = PacketOptions::from_bytes(&bytes[HEADER_SIZE..header_len])?;
+        let payload = bytes[header_len..].to_vec();
 
         Ok(StyxPacket {
+            version,
             sequence_number,
             ack_number,
             flags,
+            options,
             payload,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_packet() -> StyxPacket {
+        StyxPacket {
+            version: PROTOCOL_VERSION,
+            sequence_number: 0xDEAD_BEEF,
+            ack_number: 42,
+            flags: ACK,
+            options: PacketOptions::new(),
+            payload: b"hello, styx".to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let packet = data_packet();
+        assert_eq!(StyxPacket::from_bytes(&packet.to_bytes()), Ok(packet));
+    }
+
+    #[test]
+    fn checksum_is_the_crc32c_check_value() {
+        // The standard CRC32C check value, too short to reach the checksum field.
+        assert_eq!(checksum(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn rejects_any_flipped_bit() {
+        let bytes = data_packet().to_bytes();
+        // The magic, version and header length are checked before the
+        // checksum, so start with the sequence number.
+        for i in 4..bytes.len() {
+            for bit in 0..8 {
+                let mut corrupted = bytes.clone();
+                corrupted[i] ^= 1 << bit;
+                assert_eq!(StyxPacket::from_bytes(&corrupted), Err("Packet checksum mismatch"), "byte {} bit {}", i, bit);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_truncated_payload() {
+        let bytes = data_packet().to_bytes();
+        assert_eq!(StyxPacket::from_bytes(&bytes[..bytes.len() - 1]), Err("Packet checksum mismatch"));
+    }
+
+    #[test]
+    fn rejects_short_datagrams() {
+        let bytes = data_packet().to_bytes();
+        assert_eq!(StyxPacket::from_bytes(&bytes[..HEADER_SIZE - 1]), Err("Packet too small for header"));
+    }
+}
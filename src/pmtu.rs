@@ -0,0 +1,223 @@
+// src/pmtu.rs
+
+use std::time::{Duration, Instant};
+
+/// Datagram size every path is assumed to carry, and the size every
+/// connection starts with (BASE_PLPMTU in RFC 8899).
+pub const BASE_DATAGRAM_SIZE: usize = 1024;
+/// Largest datagram we send or receive, jumbo frames included.
+pub const MAX_DATAGRAM_SIZE: usize = 9000;
+/// Datagram sizes worth probing, common path MTUs minus the IP and UDP
+/// headers: the IPv6 minimum, Ethernet over IPv6 and IPv4, and jumbo frames
+/// over IPv6 and IPv4.
+const PROBE_SIZES: [usize; 5] = [1232, 1452, 1472, 8952, 8972];
+/// Probes of one size that may go unanswered before the size is given up on
+/// (MAX_PROBES in RFC 8899).
+const MAX_PROBES: u32 = 3;
+/// How long a completed search lasts before larger sizes are tried again,
+/// in case the path changed (PMTU_RAISE_TIMER in RFC 8899).
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// A probe on its way to the peer.
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    size: usize,
+    token: u32,
+    sent_at: Instant,
+}
+
+/// Datagram packetization layer path MTU discovery (RFC 8899): finds the
+/// largest datagram that crosses the path by sending padded probe packets
+/// of growing sizes and raising the size only once a probe has been
+/// acknowledged. Lost probes are not data, so nothing has to be resent and
+/// the congestion controller never hears of them.
+///
+/// This type only decides what to probe and when; the connection builds the
+/// probe packets and tells it what happened to them.
+#[derive(Debug, Clone)]
+pub struct PathMtu {
+    /// Largest datagram known to reach the peer.
+    current: usize,
+    /// Largest datagram the peer can receive.
+    limit: usize,
+    /// Index into `PROBE_SIZES` of the size being searched for, if searching.
+    searching: Option<usize>,
+    /// Unanswered probes of the size being searched for.
+    attempts: u32,
+    probe: Option<Probe>,
+    /// When a completed search starts over.
+    raise_at: Option<Instant>,
+    next_token: u32,
+}
+
+impl PathMtu {
+    /// Starts a search above `BASE_DATAGRAM_SIZE` for a peer that accepts
+    /// datagrams of up to `limit` bytes.
+    pub fn new(limit: usize) -> Self {
+        let mut path = PathMtu {
+            current: BASE_DATAGRAM_SIZE,
+            limit: limit.clamp(BASE_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE),
+            searching: None,
+            attempts: 0,
+            probe: None,
+            raise_at: None,
+            next_token: 0,
+        };
+        path.restart();
+        path
+    }
+
+    /// Largest datagram known to reach the peer.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Returns when `on_timeout` should next be called, if ever.
+    pub fn poll_timeout(&self, probe_timeout: Duration) -> Option<Instant> {
+        match self.probe {
+            Some(probe) => Some(probe.sent_at + probe_timeout),
+            None => self.raise_at,
+        }
+    }
+
+    /// Declares an unanswered probe lost once `probe_timeout` has passed,
+    /// giving up on its size after `MAX_PROBES` attempts, and restarts a
+    /// completed search once `RAISE_INTERVAL` is over.
+    pub fn on_timeout(&mut self, now: Instant, probe_timeout: Duration) {
+        if self.raise_at.is_some_and(|at| now >= at) {
+            self.restart();
+        }
+        match self.probe {
+            Some(probe) if now.duration_since(probe.sent_at) >= probe_timeout => {}
+            _ => return,
+        }
+        self.probe = None;
+        self.attempts += 1;
+        if self.attempts >= MAX_PROBES {
+            self.complete(now);
+        }
+    }
+
+    /// The size and token of the next probe to send, if one is due. The
+    /// caller sends it and reports back with `on_probe_sent`.
+    pub fn next_probe(&mut self) -> Option<(usize, u32)> {
+        if self.probe.is_some() {
+            return None;
+        }
+        let size = PROBE_SIZES[self.searching?];
+        self.next_token = self.next_token.wrapping_add(1);
+        Some((size, self.next_token))
+    }
+
+    /// Records that the probe returned by `next_probe` went out.
+    pub fn on_probe_sent(&mut self, size: usize, token: u32, now: Instant) {
+        self.probe = Some(Probe { size, token, sent_at: now });
+    }
+
+    /// Takes the peer's answer to probe `token`, raising the path MTU to
+    /// its size. Returns the path MTU if `token` was the probe in flight.
+    pub fn on_probe_acked(&mut self, token: u32, now: Instant) -> Option<usize> {
+        let probe = self.probe.filter(|probe| probe.token == token)?;
+        self.probe = None;
+        self.attempts = 0;
+        self.current = self.current.max(probe.size);
+        let next = self.searching.map(|index| index + 1).filter(|&index| index < PROBE_SIZES.len() && PROBE_SIZES[index] <= self.limit);
+        match next {
+            Some(index) => self.searching = Some(index),
+            None => self.complete(now),
+        }
+        Some(self.current)
+    }
+
+    /// Falls back to `BASE_DATAGRAM_SIZE` after full-sized packets kept
+    /// getting lost while smaller ones did not, which suggests the path MTU
+    /// shrank (a black hole), and searches again.
+    pub fn on_black_hole(&mut self) {
+        self.current = BASE_DATAGRAM_SIZE;
+        self.probe = None;
+        self.restart();
+    }
+
+    /// Searches from the smallest probe size again, if the peer takes any.
+    fn restart(&mut self) {
+        self.searching = (PROBE_SIZES[0] <= self.limit).then_some(0);
+        self.attempts = 0;
+        self.raise_at = None;
+    }
+
+    fn complete(&mut self, now: Instant) {
+        self.searching = None;
+        self.attempts = 0;
+        self.raise_at = Some(now + RAISE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Sends the next probe and has it answered, returning its size.
+    fn probe_answered(path: &mut PathMtu, now: Instant) -> Option<usize> {
+        let (size, token) = path.next_probe()?;
+        path.on_probe_sent(size, token, now);
+        assert_eq!(path.on_probe_acked(token, now), Some(size));
+        Some(size)
+    }
+
+    /// Sends the next probe and lets it time out, returning its size.
+    fn probe_lost(path: &mut PathMtu, now: Instant) -> Option<usize> {
+        let (size, token) = path.next_probe()?;
+        path.on_probe_sent(size, token, now);
+        assert_eq!(path.poll_timeout(PROBE_TIMEOUT), Some(now + PROBE_TIMEOUT));
+        path.on_timeout(now + PROBE_TIMEOUT, PROBE_TIMEOUT);
+        Some(size)
+    }
+
+    #[test]
+    fn answered_probes_raise_the_size_step_by_step() {
+        let now = Instant::now();
+        let mut path = PathMtu::new(MAX_DATAGRAM_SIZE);
+        assert_eq!(path.current(), BASE_DATAGRAM_SIZE);
+        let sizes: Vec<usize> = std::iter::from_fn(|| probe_answered(&mut path, now)).collect();
+        assert_eq!(sizes, PROBE_SIZES);
+        assert_eq!(path.current(), 8972);
+        assert_eq!(path.poll_timeout(PROBE_TIMEOUT), Some(now + RAISE_INTERVAL));
+
+        // The peer's limit caps the search.
+        let mut path = PathMtu::new(1500);
+        let sizes: Vec<usize> = std::iter::from_fn(|| probe_answered(&mut path, now)).collect();
+        assert_eq!(sizes, [1232, 1452, 1472]);
+        assert!(PathMtu::new(BASE_DATAGRAM_SIZE).next_probe().is_none());
+    }
+
+    #[test]
+    fn a_size_is_given_up_on_after_repeated_probe_loss() {
+        let now = Instant::now();
+        let mut path = PathMtu::new(MAX_DATAGRAM_SIZE);
+        probe_answered(&mut path, now);
+        for _ in 0..MAX_PROBES {
+            assert_eq!(probe_lost(&mut path, now), Some(1452));
+        }
+        assert_eq!(path.next_probe(), None);
+        assert_eq!(path.current(), 1232);
+        let token = path.next_token;
+        assert_eq!(path.on_probe_acked(token, now), None, "a late answer counts for nothing");
+
+        // Once the raise timer fires the search starts over from the bottom.
+        let raise_at = path.poll_timeout(PROBE_TIMEOUT).unwrap();
+        path.on_timeout(raise_at, PROBE_TIMEOUT);
+        assert_eq!(path.next_probe().map(|(size, _)| size), Some(1232));
+    }
+
+    #[test]
+    fn a_black_hole_falls_back_to_the_base_size() {
+        let now = Instant::now();
+        let mut path = PathMtu::new(MAX_DATAGRAM_SIZE);
+        while probe_answered(&mut path, now).is_some() {}
+        path.on_black_hole();
+        assert_eq!(path.current(), BASE_DATAGRAM_SIZE);
+        assert_eq!(path.next_probe().map(|(size, _)| size), Some(1232));
+    }
+}
@@ -0,0 +1,167 @@
+// src/rtt.rs
+
+use std::time::Duration;
+
+/// Retransmission timeout used before the first RTT sample (RFC 6298).
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// Lower bound for the retransmission timeout.
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+/// Upper bound for the retransmission timeout, including backoff.
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+/// Clock granularity term added to the variance when computing the RTO.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Smoothed round-trip time estimator (Jacobson/Karels, RFC 6298).
+///
+/// Callers apply Karn's rule by only feeding samples measured on segments
+/// that were never retransmitted, and call `backoff` when a retransmission
+/// timer fires.
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    /// Creates an estimator with no samples and the initial RTO.
+    pub fn new() -> Self {
+        RttEstimator::default()
+    }
+
+    /// Smoothed round-trip time, once at least one sample has been taken.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Round-trip time variance estimate.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// Current retransmission timeout, including any backoff.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Folds a new round-trip measurement into the estimate and recomputes
+    /// the RTO, which also clears any backoff.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        self.clear_backoff();
+    }
+
+    /// Recomputes the RTO from the estimate, dropping any backoff. Called
+    /// when the peer acknowledges new data that yields no sample.
+    pub fn clear_backoff(&mut self) {
+        self.rto = match self.srtt {
+            Some(srtt) => (srtt + (self.rttvar * 4).max(CLOCK_GRANULARITY)).clamp(MIN_RTO, MAX_RTO),
+            None => INITIAL_RTO,
+        };
+    }
+
+    /// Doubles the RTO after a retransmission timeout.
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn starts_with_the_initial_rto() {
+        let rtt = RttEstimator::new();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+    }
+
+    #[test]
+    fn first_sample_sets_srtt_and_half_of_it_as_variance() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(100));
+        assert_eq!(rtt.srtt(), Some(ms(100)));
+        assert_eq!(rtt.rttvar(), ms(50));
+        assert_eq!(rtt.rto(), ms(300)); // SRTT + 4 * RTTVAR
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(100));
+        rtt.sample(ms(180));
+        // RTTVAR = 3/4 * 50 + 1/4 * |100 - 180|, SRTT = 7/8 * 100 + 1/8 * 180
+        assert_eq!(rtt.rttvar(), ms(57) + Duration::from_micros(500));
+        assert_eq!(rtt.srtt(), Some(ms(110)));
+        assert_eq!(rtt.rto(), ms(340));
+    }
+
+    #[test]
+    fn rto_is_clamped() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(1));
+        assert_eq!(rtt.rto(), MIN_RTO);
+
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_secs(30));
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_until_the_next_sample() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(100));
+        rtt.backoff();
+        assert_eq!(rtt.rto(), ms(600));
+        rtt.backoff();
+        assert_eq!(rtt.rto(), ms(1200));
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+
+        rtt.sample(ms(100));
+        assert!(rtt.rto() < ms(600), "a fresh sample clears the backoff");
+    }
+
+    #[test]
+    fn clearing_the_backoff_keeps_the_estimate() {
+        let mut rtt = RttEstimator::new();
+        rtt.backoff();
+        rtt.clear_backoff();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+
+        rtt.sample(ms(100));
+        let rto = rtt.rto();
+        rtt.backoff();
+        rtt.backoff();
+        rtt.clear_backoff();
+        assert_eq!(rtt.rto(), rto);
+        assert_eq!(rtt.srtt(), Some(ms(100)));
+    }
+}
@@ -0,0 +1,101 @@
+// src/seq.rs
+
+// Serial-number arithmetic for 32-bit sequence numbers (RFC 1982).
+//
+// Sequence numbers live on a circle: adding wraps around at 2^32, and two
+// numbers are compared by the sign of their wrapping difference. Comparisons
+// are meaningful as long as the numbers are less than 2^31 apart, which the
+// send and receive windows guarantee.
+
+/// Advances sequence number `seq` by `len` bytes, wrapping at 2^32.
+pub fn seq_add(seq: u32, len: usize) -> u32 {
+    seq.wrapping_add(len as u32)
+}
+
+/// Moves sequence number `seq` back by `len` bytes, wrapping at 2^32.
+pub fn seq_sub(seq: u32, len: usize) -> u32 {
+    seq.wrapping_sub(len as u32)
+}
+
+/// Number of bytes from `from` forward to `to`. Only meaningful when
+/// `from` does not come after `to`.
+pub fn seq_distance(from: u32, to: u32) -> usize {
+    to.wrapping_sub(from) as usize
+}
+
+/// Returns true if sequence number `a` comes before `b`.
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Returns true if sequence number `a` comes before or equals `b`.
+pub fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Returns true if sequence number `a` comes after `b`.
+pub fn seq_gt(a: u32, b: u32) -> bool {
+    seq_lt(b, a)
+}
+
+/// Returns true if sequence number `a` comes after or equals `b`.
+pub fn seq_ge(a: u32, b: u32) -> bool {
+    seq_le(b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons_hold_across_the_wrap() {
+        let before = u32::MAX - 10;
+        let after = 10;
+        assert!(seq_lt(before, after));
+        assert!(seq_le(before, after));
+        assert!(seq_gt(after, before));
+        assert!(seq_ge(after, before));
+        assert!(!seq_lt(after, before));
+        assert!(!seq_gt(before, after));
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(seq_gt(0, u32::MAX));
+    }
+
+    #[test]
+    fn comparisons_are_reflexive_only_for_le_and_ge() {
+        for seq in [0, 1, u32::MAX / 2, u32::MAX] {
+            assert!(seq_le(seq, seq));
+            assert!(seq_ge(seq, seq));
+            assert!(!seq_lt(seq, seq));
+            assert!(!seq_gt(seq, seq));
+        }
+    }
+
+    #[test]
+    fn comparisons_follow_the_shorter_way_round() {
+        // Less than half the space ahead comes after, more than half comes
+        // before, wherever on the circle we start.
+        let half = 1u32 << 31;
+        assert!(seq_lt(0, half - 1));
+        assert!(seq_gt(0, half + 1));
+        assert!(seq_lt(u32::MAX - 5, (half - 1).wrapping_add(u32::MAX - 5)));
+    }
+
+    #[test]
+    fn addition_and_subtraction_wrap() {
+        assert_eq!(seq_add(u32::MAX, 1), 0);
+        assert_eq!(seq_add(u32::MAX - 1, 5), 3);
+        assert_eq!(seq_sub(0, 1), u32::MAX);
+        assert_eq!(seq_sub(3, 5), u32::MAX - 1);
+        assert_eq!(seq_sub(seq_add(u32::MAX - 100, 1000), 1000), u32::MAX - 100);
+    }
+
+    #[test]
+    fn distance_counts_forward_across_the_wrap() {
+        assert_eq!(seq_distance(u32::MAX - 1, 2), 4);
+        assert_eq!(seq_distance(u32::MAX, u32::MAX), 0);
+        assert_eq!(seq_distance(5, 5 + 1000), 1000);
+        let start = u32::MAX - 500;
+        assert_eq!(seq_distance(start, seq_add(start, 65_536)), 65_536);
+    }
+}
@@ -17,6 +17,8 @@ pub enum ConnectionState {
     FinWait1,
     /// The active closer has received an ACK for its FIN and is waiting for the peer's FIN.
     FinWait2,
+    /// Both sides sent a FIN at once; the peer's FIN is ACK'd and ours is still unacknowledged.
+    Closing,
     /// The passive closer has received a FIN and will send its own FIN after the application closes.
     CloseWait,
     /// The passive closer has sent its FIN and is waiting for the final ACK.
@@ -24,3 +26,93 @@ pub enum ConnectionState {
     /// The active closer waits for a short period to ensure the final ACK was received.
     TimeWait,
 }
+
+impl ConnectionState {
+    /// Returns true if the state machine allows moving from `self` to `next`.
+    /// Every state may drop to `Closed`, which is how an abort is expressed.
+    pub fn can_transition_to(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (_, Closed)
+                | (Closed, Listen)
+                | (Closed, SynSent)
+                | (Listen, SynReceived)
+                | (SynSent, Established)
+                | (SynReceived, Established)
+                | (Established, FinWait1)
+                | (Established, CloseWait)
+                | (FinWait1, FinWait2)
+                | (FinWait1, Closing)
+                | (FinWait2, TimeWait)
+                | (Closing, TimeWait)
+                | (CloseWait, LastAck)
+        )
+    }
+
+    /// Returns true if the application may still send data in this state.
+    pub fn can_send(self) -> bool {
+        matches!(self, ConnectionState::Established | ConnectionState::CloseWait)
+    }
+
+    /// Returns true if the peer may still send us data in this state.
+    pub fn can_receive(self) -> bool {
+        matches!(self, ConnectionState::Established | ConnectionState::FinWait1 | ConnectionState::FinWait2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionState::{self, *};
+
+    const ALL: [ConnectionState; 11] = [
+        Closed, Listen, SynSent, SynReceived, Established, FinWait1, FinWait2, Closing, CloseWait, LastAck, TimeWait,
+    ];
+
+    #[test]
+    fn transitions_follow_the_state_machine() {
+        let allowed = [
+            (Closed, Listen),
+            (Closed, SynSent),
+            (Listen, SynReceived),
+            (SynSent, Established),
+            (SynReceived, Established),
+            (Established, FinWait1),
+            (Established, CloseWait),
+            (FinWait1, FinWait2),
+            (FinWait1, Closing),
+            (FinWait2, TimeWait),
+            (Closing, TimeWait),
+            (CloseWait, LastAck),
+        ];
+        for from in ALL {
+            for to in ALL {
+                let expected = to == Closed || allowed.contains(&(from, to));
+                assert_eq!(from.can_transition_to(to), expected, "{:?} -> {:?}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn only_open_directions_carry_data() {
+        let table = [
+            // (state, can_send, can_receive)
+            (Closed, false, false),
+            (Listen, false, false),
+            (SynSent, false, false),
+            (SynReceived, false, false),
+            (Established, true, true),
+            (FinWait1, false, true),
+            (FinWait2, false, true),
+            (Closing, false, false),
+            (CloseWait, true, false),
+            (LastAck, false, false),
+            (TimeWait, false, false),
+        ];
+        assert_eq!(table.len(), ALL.len());
+        for (state, send, receive) in table {
+            assert_eq!(state.can_send(), send, "can_send in {:?}", state);
+            assert_eq!(state.can_receive(), receive, "can_receive in {:?}", state);
+        }
+    }
+}
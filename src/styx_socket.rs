@@ -1,300 +1,506 @@
-use crate::packet::{StyxPacket, ACK, FIN, SYN};
+use crate::congestion::CongestionController;
+use crate::connection::{Connection, Stats};
+use crate::packet::{StyxPacket, FIN};
+use crate::pmtu::MAX_DATAGRAM_SIZE;
 use crate::state::ConnectionState;
+use crate::transport::{disable_fragmentation, dropped_as_too_large, listener_gone, Backlog, Demux, Transport};
+use std::io::{Read, Write};
 use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
-const DATA_TIMEOUT: Duration = Duration::from_millis(500);
+pub use crate::connection::{RetransmitPolicy, MAX_PAYLOAD_SIZE};
 
 /// A reliable socket built on top of UDP.
+///
+/// All protocol logic lives in `Connection`; a `StyxSocket` only moves
+/// datagrams between it and the network and blocks the caller until the
+/// connection can make progress.
 pub struct StyxSocket {
-    socket: UdpSocket,
-    peer_addr: Option<SocketAddr>,
-    state: ConnectionState,
-    sequence_number: u32,
-    ack_number: u32,
+    socket: Transport,
+    connection: Connection,
 }
 
 impl StyxSocket {
-    /// Binds the socket to a local address.
+    /// Binds the socket to a local address. Datagrams arriving on it are
+    /// demultiplexed by source address, so any number of connections accepted
+    /// from this socket can be served concurrently.
     pub fn bind(addr: &str) -> std::io::Result<Self> {
         let socket = UdpSocket::bind(addr)?;
-        socket.set_read_timeout(None)?; // Listen indefinitely
-        Ok(StyxSocket {
-            socket,
-            peer_addr: None,
-            state: ConnectionState::Listen,
-            sequence_number: 0, // Initialized during handshake
-            ack_number: 0,
-        })
+        disable_fragmentation(&socket, socket.local_addr()?)?;
+        let (demux, pending) = Demux::spawn(socket)?;
+        let socket = Transport::Listening {
+            demux,
+            backlog: Mutex::new(Backlog::new(pending)),
+        };
+        let connection = Connection::listen(socket.local_addr()?)?;
+        Ok(StyxSocket { socket, connection })
     }
 
     /// Listens for an incoming connection and performs the 3-way handshake.
     /// Returns a new StyxSocket for the established connection.
+    /// The returned connection is independent of the listener and may be
+    /// moved to another thread while the listener accepts further peers.
+    ///
+    /// Handshakes run side by side: every call drives all of them and
+    /// returns whichever completes first, so a peer that never sends its
+    /// final ACK holds up nobody else. Handshakes still open are kept for
+    /// the next call.
+    ///
+    /// With SYN cookies enabled the listener keeps no state for a SYN: the
+    /// SYN-ACK's sequence number encodes the connection parameters, and the
+    /// connection is only created once an ACK echoing a valid cookie returns.
     pub fn listen_and_accept(&self) -> std::io::Result<Self> {
-        let mut buf = [0; 1024];
+        let (demux, backlog) = match &self.socket {
+            Transport::Listening { demux, backlog } => (demux, backlog),
+            _ => return Err(self.connection.wrong_state("accept connections")),
+        };
+        let mut backlog = backlog.lock().unwrap();
         println!("Server is in Listen state, waiting for SYN...");
 
-        // 1. Wait for SYN
-        let (amt, src) = self.socket.recv_from(&mut buf)?;
-        let received_packet = StyxPacket::from_bytes(&buf[..amt]).unwrap();
-
-        if received_packet.flags == SYN {
-            println!("1. Received SYN from {}: {:?}", src, received_packet);
-            let client_isn = received_packet.sequence_number;
-
-            // Create a new socket for the connection
-            let new_socket = self.socket.try_clone()?;
-            new_socket.connect(src)?;
-            new_socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
-
-            let mut connection = StyxSocket {
-                socket: new_socket,
-                peer_addr: Some(src),
-                state: ConnectionState::SynReceived,
-                sequence_number: rand::random::<u32>() % 1000, // Server's ISN
-                ack_number: client_isn + 1,
+        loop {
+            while let Some((peer, bytes)) = backlog.handshakes.poll_transmit() {
+                demux.send_to(&bytes, peer)?;
+            }
+            // Wait for a datagram from a peer without a connection, or until
+            // a handshake timer is due.
+            let received = match backlog.deferred.pop_front() {
+                Some(received) => Some(received),
+                None => match backlog.handshakes.poll_timeout() {
+                    Some(at) => match backlog.pending.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                        Ok(received) => Some(received),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return Err(listener_gone()),
+                    },
+                    None => Some(backlog.pending.recv().map_err(|_| listener_gone())?),
+                },
             };
+            let now = Instant::now();
+            backlog.handshakes.handle_timeout(now);
+            let connection = match received {
+                Some((src, datagram)) => backlog.handshakes.handle_datagram(&self.connection, src, &datagram, now)?,
+                None => None,
+            };
+            let connection = match connection {
+                Some(connection) => connection,
+                None => continue,
+            };
+            while let Some((peer, bytes)) = backlog.handshakes.poll_transmit() {
+                demux.send_to(&bytes, peer)?;
+            }
 
-            // 2. Send SYN-ACK
-            let syn_ack_packet = StyxPacket {
-                sequence_number: connection.sequence_number,
-                ack_number: connection.ack_number,
-                flags: SYN | ACK,
-                payload: Vec::new(),
+            // Route everything else this peer sends to the new connection. A
+            // peer that still has a live connection keeps it.
+            let peer = connection.peer_addr().unwrap();
+            let incoming = match demux.register(peer) {
+                Some(incoming) => incoming,
+                None => continue,
             };
-            println!("2. Sending SYN-ACK...");
-            connection.socket.send(&syn_ack_packet.to_bytes())?;
-
-            // 3. Wait for final ACK
-            let (amt, _) = connection.socket.recv_from(&mut buf)?;
-            let final_ack_packet = StyxPacket::from_bytes(&buf[..amt]).unwrap();
-
-            if final_ack_packet.flags == ACK && final_ack_packet.ack_number == connection.sequence_number + 1 {
-                println!("3. Received final ACK. Handshake successful!");
-                connection.state = ConnectionState::Established;
-                connection.sequence_number += 1; // IMPORTANT: Increment sequence number after SYN is ACK'd
-                return Ok(connection);
-            } else {
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid final ACK"));
+            let socket = Transport::Demuxed {
+                demux: demux.clone(),
+                peer,
+                incoming,
+                read_timeout: None,
+            };
+            let mut accepted = StyxSocket { socket, connection };
+            let queued = backlog.pending.try_iter().collect();
+            for datagram in backlog.take_early(peer, queued) {
+                accepted.connection.handle_datagram(&datagram, now)?;
             }
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Expected SYN packet"))
+            accepted.flush_transmits()?;
+            return Ok(accepted);
         }
     }
 
     /// Connects to a remote address.
     pub fn connect(addr: &str) -> std::io::Result<Self> {
+        StyxSocket::connect_with_policy(addr, RetransmitPolicy::default())
+    }
+
+    /// Connects to a remote address, giving up on the handshake and later
+    /// retransmissions as `policy` dictates.
+    pub fn connect_with_policy(addr: &str, policy: RetransmitPolicy) -> std::io::Result<Self> {
         let socket = UdpSocket::bind("127.0.0.1:0")?;
+        disable_fragmentation(&socket, socket.local_addr()?)?;
         socket.connect(addr)?;
-        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
-
-        let client_isn = rand::random::<u32>() % 1000;
+        let connection = Connection::connect(socket.local_addr()?, socket.peer_addr()?, policy, Instant::now())?;
         let mut connection = StyxSocket {
-            socket,
-            peer_addr: Some(addr.parse().unwrap()),
-            state: ConnectionState::SynSent,
-            sequence_number: client_isn,
-            ack_number: 0,
+            socket: Transport::Connected(socket),
+            connection,
         };
+        connection.wait_until_open()?;
+        Ok(connection)
+    }
 
-        // 1. Send SYN
-        let syn_packet = StyxPacket {
-            sequence_number: client_isn,
-            ack_number: 0,
-            flags: SYN,
-            payload: Vec::new(),
-        };
-        println!("1. Sending SYN...");
-        connection.socket.send(&syn_packet.to_bytes())?;
-
-        // 2. Wait for SYN-ACK
-        let mut buf = [0; 1024];
-        let (amt, _) = connection.socket.recv_from(&mut buf)?;
-        let syn_ack_packet = StyxPacket::from_bytes(&buf[..amt]).unwrap();
-
-        if syn_ack_packet.flags == (SYN | ACK) && syn_ack_packet.ack_number == client_isn + 1 {
-            println!("2. Received SYN-ACK: {:?}", syn_ack_packet);
-            connection.state = ConnectionState::Established;
-            connection.ack_number = syn_ack_packet.sequence_number + 1;
-            connection.sequence_number = syn_ack_packet.ack_number;
-
-            // 3. Send final ACK
-            let ack_packet = StyxPacket {
-                sequence_number: connection.sequence_number,
-                ack_number: connection.ack_number,
-                flags: ACK,
-                payload: Vec::new(),
-            };
-            println!("3. Sending final ACK...");
-            connection.socket.send(&ack_packet.to_bytes())?;
+    /// Drives the handshake until the connection is established or has
+    /// given up.
+    fn wait_until_open(&mut self) -> std::io::Result<()> {
+        while matches!(self.connection.state(), ConnectionState::SynSent | ConnectionState::SynReceived) {
+            self.step()?;
+        }
+        if let Some(e) = self.connection.error() {
+            return Err(e);
+        }
+        self.socket.set_read_timeout(None)
+    }
 
-            println!("Handshake successful! Connection Established.");
-            return Ok(connection);
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid SYN-ACK"))
+    /// Sends every datagram the connection has queued.
+    fn flush_transmits(&mut self) -> std::io::Result<()> {
+        while let Some(bytes) = self.connection.poll_transmit() {
+            match self.socket.send(&bytes) {
+                Err(e) if !dropped_as_too_large(&e, bytes.len()) => return Err(e),
+                _ => {}
+            }
         }
+        Ok(())
+    }
+
+    /// Blocks until a datagram arrives or the connection's next timer is
+    /// due, feeds whichever happened to the connection and sends its replies.
+    fn step(&mut self) -> std::io::Result<()> {
+        self.flush_transmits()?;
+        // A zero read timeout is rejected by `UdpSocket`, so a due timer waits 1ms.
+        let timeout = self.connection.poll_timeout().map(|at| at.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+        self.socket.set_read_timeout(timeout)?;
+
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        match self.socket.recv(&mut buf) {
+            Ok(amt) => self.connection.handle_datagram(&buf[..amt], Instant::now())?,
+            Err(ref e) if is_timeout(e) => {}
+            Err(e) => return Err(e),
+        }
+        self.connection.handle_timeout(Instant::now())?;
+        self.flush_transmits()?;
+        // A blocking caller learns everything it needs from the state itself.
+        while self.connection.poll_event().is_some() {}
+        Ok(())
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.connection.state()
     }
 
     /// Returns the socket address of the remote peer, if connected.
     pub fn peer_addr(&self) -> Option<SocketAddr> {
-        self.peer_addr
+        self.connection.peer_addr()
+    }
+
+    /// Returns the policy that decides when retransmission gives up.
+    pub fn retransmit_policy(&self) -> RetransmitPolicy {
+        self.connection.retransmit_policy()
+    }
+
+    /// Sets when retransmission gives up. On a listening socket the policy
+    /// is inherited by every connection it accepts afterwards.
+    pub fn set_retransmit_policy(&mut self, policy: RetransmitPolicy) {
+        self.connection.set_retransmit_policy(policy);
+    }
+
+    /// Returns true if this listener answers SYNs with SYN cookies.
+    pub fn syn_cookies(&self) -> bool {
+        self.connection.syn_cookies()
+    }
+
+    /// Makes a listener answer every SYN statelessly with a SYN cookie, not
+    /// only once 128 handshakes are half-open. The SYN-ACK is then not
+    /// retransmitted: a client whose SYN-ACK is lost retransmits its SYN
+    /// instead, but a lost final ACK goes unnoticed until the client sends
+    /// data, so a protocol where the server speaks first hangs.
+    pub fn set_syn_cookies(&mut self, enabled: bool) {
+        self.connection.set_syn_cookies(enabled);
+    }
+
+    /// Returns the current retransmission timeout, including any backoff.
+    pub fn rto(&self) -> Duration {
+        self.connection.rto()
+    }
+
+    /// Returns the smoothed round-trip time, once it has been measured.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.connection.srtt()
+    }
+
+    /// Returns how many datagrams were dropped for failing their checksum or
+    /// being too short to hold a header.
+    pub fn corrupted_packets(&self) -> u64 {
+        self.connection.corrupted_packets()
+    }
+
+    /// Returns the maximum number of unacknowledged data packets allowed in
+    /// flight, `usize::MAX` unless capped with `set_window_size`.
+    pub fn window_size(&self) -> usize {
+        self.connection.window_size()
+    }
+
+    /// Caps the send window in packets, on top of the congestion window. A
+    /// size of 1 degrades to Stop-and-Wait. On a listening socket the cap is
+    /// inherited by every accepted connection.
+    pub fn set_window_size(&mut self, size: usize) {
+        self.connection.set_window_size(size);
+    }
+
+    /// Returns how many bytes the congestion controller lets us have in flight.
+    pub fn congestion_window(&self) -> usize {
+        self.connection.congestion_window()
+    }
+
+    /// Selects the congestion controller: `NewReno` (the default), `Cubic`,
+    /// the pacing `Bbr`, or your own `CongestionController`. On a listening socket every
+    /// accepted connection starts with a copy of it.
+    pub fn set_congestion_controller(&mut self, controller: Box<dyn CongestionController>) {
+        self.connection.set_congestion_controller(controller);
+    }
+
+    /// Returns a snapshot of the round-trip, congestion and pacing state,
+    /// such as the delivery rate measured and the rate `Bbr` paces at.
+    pub fn stats(&self) -> Stats {
+        self.connection.stats()
+    }
+
+    /// Returns the most received data buffered for the application at once.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.connection.recv_buffer_size()
+    }
+
+    /// Sets the receive buffer. Only its free space is advertised to the
+    /// peer as our receive window, so a reader that falls behind slows the
+    /// sender down instead of being overrun. The size is capped at
+    /// `MAX_RECV_BUFFER_SIZE` (1 GiB). On a listening socket the size is
+    /// inherited by every accepted connection.
+    pub fn set_recv_buffer_size(&mut self, size: usize) -> std::io::Result<()> {
+        self.connection.set_recv_buffer_size(size);
+        self.flush_transmits()
     }
 
+    /// Returns whether data is acknowledged at once rather than after a delay.
+    pub fn quickack(&self) -> bool {
+        self.connection.quickack()
+    }
+
+    /// Acknowledges every data segment as soon as it arrives, like
+    /// `TCP_QUICKACK`. By default ACKs are delayed to cover every second
+    /// segment, or ride on data we send, which halves the packets a
+    /// receive-heavy stream costs. A blocking socket sends a delayed ACK
+    /// during its next call, so a receiver that pauses between reads may
+    /// want this. On a listening socket the setting is inherited by every
+    /// accepted connection.
+    pub fn set_quickack(&mut self, enabled: bool) -> std::io::Result<()> {
+        self.connection.set_quickack(enabled);
+        self.flush_transmits()
+    }
+
+    /// Returns whether small packets are sent at once.
+    pub fn nodelay(&self) -> bool {
+        self.connection.nodelay()
+    }
+
+    /// Sends every write at once, like `TCP_NODELAY`. By default a packet
+    /// smaller than the segment size waits while earlier data is
+    /// unacknowledged, so an application writing a few bytes at a time
+    /// produces full packets instead of thousands of tiny ones (Nagle's
+    /// algorithm). Latency-sensitive callers can enable this, or call
+    /// `flush` after each message. On a listening socket the setting is
+    /// inherited by every accepted connection.
+    pub fn set_nodelay(&mut self, enabled: bool) -> std::io::Result<()> {
+        self.connection.set_nodelay(enabled, Instant::now());
+        self.flush_transmits()
+    }
+
+    /// Queues `data` in packets of at most the segment size (see `Stats::mss`)
+    /// and transmits them as the windows allow. A final packet smaller than that
+    /// may wait for earlier data to be acknowledged, so later writes can
+    /// fill it up (see `set_nodelay`). Blocks only while the send window is
+    /// full; use `flush` to send everything and wait for it to be
+    /// acknowledged.
     pub fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
-        let data_packet = StyxPacket {
-            sequence_number: self.sequence_number,
-            ack_number: self.ack_number,
-            flags: 0, // A pure data packet has no flags
-            payload: data.to_vec(),
-        };
-        let packet_bytes = data_packet.to_bytes();
+        let mut sent = 0;
+        loop {
+            match self.connection.send(&data[sent..], Instant::now()) {
+                Ok(amt) => sent += amt,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => self.step()?,
+                Err(e) => return Err(e),
+            }
+            if sent == data.len() {
+                return self.flush_transmits();
+            }
+        }
+    }
 
-        self.socket.set_read_timeout(Some(DATA_TIMEOUT))?;
+    /// Sends `message` as one message, whatever its size: it is split into
+    /// packets of at most the segment size and `recv_message` at the peer
+    /// puts it back together, so message boundaries survive even though the
+    /// data travels as a stream. Blocks only while the send window is full.
+    pub fn send_message(&mut self, message: &[u8]) -> std::io::Result<()> {
+        let mut sent = 0;
+        loop {
+            match self.connection.send_message(&message[sent..], Instant::now()) {
+                Ok(amt) => sent += amt,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => self.step()?,
+                Err(e) => return Err(e),
+            }
+            if sent == message.len() {
+                return self.flush_transmits();
+            }
+        }
+    }
 
+    /// Blocks until a whole message sent with `send_message` has arrived and
+    /// returns it. Returns `Ok(None)` once the peer has closed the connection;
+    /// `close` then completes the passive close.
+    pub fn recv_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
         loop {
-            self.socket.send(&packet_bytes)?;
-            println!("  -> Sent data (seq: {}), waiting for ACK...", self.sequence_number);
-
-            let mut buf = [0; 1024];
-            match self.socket.recv_from(&mut buf) {
-                Ok((amt, _)) => {
-                    if let Ok(ack_packet) = StyxPacket::from_bytes(&buf[..amt]) {
-                        if (ack_packet.flags & ACK) != 0 && ack_packet.ack_number == self.sequence_number + 1 {
-                            self.socket.set_read_timeout(None)?;
-                            self.sequence_number += 1; // Increment for the next packet we send
-                            self.ack_number = ack_packet.sequence_number + 1;
-                            println!("  <- Received ACK for seq {}.", self.sequence_number - 1);
-                            return Ok(());
-                        }
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    println!("  -> ACK not received, retransmitting...");
-                    continue; // Timeout, retransmit
+            match self.connection.recv_message() {
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => self.step()?,
+                result => {
+                    self.flush_transmits()?; // Taking data may reopen the window, reaching EOF ACKs the FIN
+                    return result;
                 }
-                Err(e) => return Err(e), // Other error
             }
         }
     }
 
-    pub fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.socket.set_read_timeout(None)?; // Wait indefinitely for data
+    /// Sends any data held back to coalesce small writes and blocks until
+    /// everything has been acknowledged.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.connection.flush(Instant::now());
+        while !self.connection.is_flushed() {
+            self.step()?;
+        }
+        if let Some(e) = self.connection.error() {
+            return Err(e);
+        }
+        self.socket.set_read_timeout(None)
+    }
 
+    /// Receives the next in-order packet, writing its serialized form into `buf`.
+    /// Out-of-order data is buffered and every arrival is answered with a
+    /// cumulative ACK for the next byte we expect.
+    pub fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         loop {
-            let (amt, _) = self.socket.recv_from(buf)?;
-            if let Ok(packet) = StyxPacket::from_bytes(&buf[..amt]) {
-                // If it's a data packet (not FIN, not pure ACK)
-                if (packet.flags & (FIN | SYN)) == 0 {
-                    println!("  <- Received data (seq: {}), sending ACK.", packet.sequence_number);
-                    self.ack_number = packet.sequence_number + 1;
-
-                    let ack_packet = StyxPacket {
-                        sequence_number: self.sequence_number,
-                        ack_number: self.ack_number,
-                        flags: ACK,
-                        payload: Vec::new(),
-                    };
-                    self.socket.send(&ack_packet.to_bytes())?;
-                    return Ok(amt);
-                } else if (packet.flags & FIN) != 0 {
-                    // If we received a FIN while expecting data, pass it up.
-                    return Ok(amt);
-                }
-                // Ignore other packets like stray ACKs from previous transmissions
+            if let Some(packet) = self.connection.recv_packet()? {
+                self.flush_transmits()?; // Taking a packet may reopen the receive window
+                let bytes = packet.to_bytes();
+                let amt = bytes.len().min(buf.len());
+                buf[..amt].copy_from_slice(&bytes[..amt]);
+                return Ok(amt);
             }
+            self.step()?;
         }
     }
 
-        pub fn handle_passive_close(&mut self, client_fin_packet: StyxPacket) -> std::io::Result<()> {
-        // 1. Send ACK for client's FIN
-        self.state = ConnectionState::CloseWait;
-        let ack_packet = StyxPacket {
-            sequence_number: client_fin_packet.ack_number, // Our seq is their ack
-            ack_number: client_fin_packet.sequence_number + 1,
-            flags: ACK,
-            payload: Vec::new(),
-        };
-        println!("  - Sending ACK for FIN...");
-        self.socket.send(&ack_packet.to_bytes())?;
-
-        // 2. Send our own FIN
-        self.state = ConnectionState::LastAck;
-        let fin_packet = StyxPacket {
-            sequence_number: ack_packet.sequence_number, // Sequence number continues
-            ack_number: 0, // Not acknowledging anything
-            flags: FIN,
-            payload: Vec::new(),
-        };
-        println!("  - Sending our own FIN...");
-        self.socket.send(&fin_packet.to_bytes())?;
-
-        // 3. Wait for final ACK
-        let mut buf = [0; 1024];
-        let (amt, _) = self.socket.recv_from(&mut buf)?;
-        let final_ack = StyxPacket::from_bytes(&buf[..amt]).unwrap();
-
-        if (final_ack.flags & ACK) != 0 && final_ack.ack_number == fin_packet.sequence_number + 1 {
-            println!("  - Received final ACK. Connection is closed.");
-            self.state = ConnectionState::Closed;
-            Ok(())
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Did not receive final ACK"))
+    /// Completes the passive close after the peer's FIN was returned by `recv`.
+    /// The FIN itself was acknowledged as soon as it arrived.
+    pub fn handle_passive_close(&mut self, client_fin_packet: StyxPacket) -> std::io::Result<()> {
+        let is_fin = (client_fin_packet.flags & FIN) != 0;
+        if self.connection.state() != ConnectionState::CloseWait || !is_fin {
+            return Err(self.connection.wrong_state("complete a passive close"));
         }
+        self.close()
     }
 
+    /// Closes the connection. Once the peer's FIN has arrived this finishes
+    /// the passive close; otherwise it starts the active close.
+    ///
+    /// Our FIN is retransmitted like data until it is acknowledged, and a
+    /// duplicate FIN from the peer is re-ACK'd in every closing state. The
+    /// active closer lingers in `TimeWait` so that it can answer a FIN whose
+    /// final ACK was lost. Both sides closing at once meet in `Closing`.
     pub fn close(&mut self) -> std::io::Result<()> {
-        // 1. Send FIN (Client enters FinWait1)
-        self.state = ConnectionState::FinWait1;
-        let fin_packet = StyxPacket {
-            sequence_number: self.sequence_number,
-            ack_number: 0, // Not acknowledging anything
-            flags: FIN,
-            payload: Vec::new(),
-        };
-        println!("4. Sending FIN...");
-        self.socket.send(&fin_packet.to_bytes())?;
-
-        // 2. Wait for ACK from server
-        let mut buf = [0; 1024];
-        let (amt, _) = self.socket.recv_from(&mut buf)?;
-        let ack_packet = StyxPacket::from_bytes(&buf[..amt]).unwrap();
-
-        if ack_packet.flags == ACK && ack_packet.ack_number == self.sequence_number + 1 {
-            self.state = ConnectionState::FinWait2;
-            println!("5. Received ACK for FIN.");
-        } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Did not receive valid ACK for FIN"));
+        // The FIN goes out behind anything still in the send window.
+        self.connection.close(Instant::now())?;
+        while self.connection.state() != ConnectionState::Closed {
+            self.step()?;
+        }
+        if let Some(e) = self.connection.error() {
+            return Err(e);
         }
+        self.socket.set_read_timeout(None)
+    }
+}
 
-        // 3. Wait for FIN from server
-        let (amt, _) = self.socket.recv_from(&mut buf)?;
-        let server_fin_packet = StyxPacket::from_bytes(&buf[..amt]).unwrap();
+impl Read for StyxSocket {
+    /// Reads payload bytes in stream order. Returns `Ok(0)` once the peer's
+    /// FIN has been reached; the FIN is ACK'd and `close` then completes the
+    /// passive close.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.connection.read(buf) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => self.step()?,
+                result => {
+                    self.flush_transmits()?; // Reading the FIN queues its ACK
+                    return result;
+                }
+            }
+        }
+    }
+}
 
-        if (server_fin_packet.flags & FIN) != 0 {
-            println!("6. Received FIN from server.");
-            self.ack_number = server_fin_packet.sequence_number + 1;
+impl Write for StyxSocket {
+    /// Sends up to one packet's worth of `buf`, blocking only while the send
+    /// window is full.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let amt = buf.len().min(self.connection.mss());
+        self.send(&buf[..amt])?;
+        Ok(amt)
+    }
 
-            // 4. Send final ACK (Client enters TimeWait)
-            let final_ack_packet = StyxPacket {
-                sequence_number: ack_packet.ack_number, // Our sequence number is their ack number
-                ack_number: self.ack_number,
-                flags: ACK,
-                payload: Vec::new(),
-            };
-            println!("7. Sending final ACK...");
-            self.socket.send(&final_ack_packet.to_bytes())?;
-            self.state = ConnectionState::TimeWait;
-
-            println!("Entering TimeWait state...");
-            // In a real implementation, we'd wait here without blocking the whole program.
-            // For this simulation, a simple sleep is fine.
-            std::thread::sleep(Duration::from_secs(2));
-            self.state = ConnectionState::Closed;
-            println!("Connection closed successfully.");
-            Ok(())
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Expected FIN from server"))
-        }
+    /// Blocks until everything written has been acknowledged by the peer.
+    fn flush(&mut self) -> std::io::Result<()> {
+        StyxSocket::flush(self)
+    }
+}
+
+/// Returns true for the errors a read timeout produces (`WouldBlock` on Unix,
+/// `TimedOut` on Windows).
+fn is_timeout(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A SYN from a peer at `from` that will never answer the SYN-ACK.
+    fn unanswered_syn(from: &UdpSocket, to: SocketAddr) {
+        let mut syn_sender = Connection::connect(from.local_addr().unwrap(), to, RetransmitPolicy::default(), Instant::now()).unwrap();
+        from.send_to(&syn_sender.poll_transmit().unwrap(), to).unwrap();
+    }
+
+    #[test]
+    fn an_unanswered_syn_does_not_delay_other_clients() {
+        let listener = StyxSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.socket.local_addr().unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        unanswered_syn(&silent, addr);
+        thread::sleep(Duration::from_millis(50)); // Let the listener see it first
+
+        let started = Instant::now();
+        let client = thread::spawn(move || StyxSocket::connect(&addr.to_string()).unwrap());
+        let accepted = listener.listen_and_accept().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2), "accept took {:?}", started.elapsed());
+        let client = client.join().unwrap();
+        assert_eq!(accepted.peer_addr(), Some(client.socket.local_addr().unwrap()));
+        assert_eq!(accepted.state(), ConnectionState::Established);
+    }
+
+    #[test]
+    fn data_right_behind_the_final_ack_reaches_the_new_connection() {
+        let listener = StyxSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.socket.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = StyxSocket::connect(&addr.to_string()).unwrap();
+            client.write_all(b"hello").unwrap();
+            client.close().unwrap();
+            client.stats().retransmitted_packets
+        });
+        let mut accepted = listener.listen_and_accept().unwrap();
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"hello");
+        accepted.close().unwrap();
+        assert_eq!(client.join().unwrap(), 0, "the data was not lost to the handover");
     }
 }
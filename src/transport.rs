@@ -0,0 +1,548 @@
+// src/transport.rs
+
+use crate::connection::{Connection, ListenerAction};
+use crate::pmtu::MAX_DATAGRAM_SIZE;
+use crate::state::ConnectionState;
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a demultiplexer wakes up to check whether anyone still uses it.
+pub(crate) const DEMUX_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Datagrams queued for one connection before further ones are dropped, as
+/// a full UDP receive buffer would drop them.
+pub(crate) const CONNECTION_QUEUE_LEN: usize = 1024;
+/// Datagrams from peers without a connection queued for the listener before
+/// further ones are dropped, so a SYN flood cannot grow memory without limit.
+pub(crate) const PENDING_QUEUE_LEN: usize = 256;
+/// Most handshakes a listener keeps state for at once. SYNs beyond that are
+/// answered with SYN cookies, so a flood of spoofed SYNs locks nobody out.
+pub(crate) const MAX_HALF_OPEN: usize = 128;
+
+/// A datagram from a peer with no connection yet, queued for `listen_and_accept`.
+pub(crate) type PendingDatagram = (SocketAddr, Vec<u8>);
+
+/// The sending half of a connection's bounded datagram queue.
+pub(crate) trait DatagramQueue {
+    /// Queues `datagram`, or drops it if the queue is full. Returns false
+    /// if the connection has gone away.
+    fn push(&self, datagram: Vec<u8>) -> bool;
+}
+
+impl DatagramQueue for SyncSender<Vec<u8>> {
+    fn push(&self, datagram: Vec<u8>) -> bool {
+        !matches!(self.try_send(datagram), Err(TrySendError::Disconnected(_)))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl DatagramQueue for tokio::sync::mpsc::Sender<Vec<u8>> {
+    fn push(&self, datagram: Vec<u8>) -> bool {
+        !matches!(self.try_send(datagram), Err(tokio::sync::mpsc::error::TrySendError::Closed(_)))
+    }
+}
+
+/// A listener's per-connection queues keyed by source address, shared by
+/// the blocking and the tokio demultiplexer.
+pub(crate) struct Routes<Q> {
+    connections: Mutex<HashMap<SocketAddr, Q>>,
+}
+
+impl<Q: DatagramQueue> Routes<Q> {
+    pub(crate) fn new() -> Self {
+        Routes {
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Routes all future datagrams from `peer` to `queue`, or returns false
+    /// if a live connection already owns that address.
+    pub(crate) fn register(&self, peer: SocketAddr, queue: Q) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(&peer) {
+            return false;
+        }
+        connections.insert(peer, queue);
+        true
+    }
+
+    pub(crate) fn unregister(&self, peer: SocketAddr) {
+        self.connections.lock().unwrap().remove(&peer);
+    }
+
+    /// Hands a datagram from `src` to the connection that owns `src`, or to
+    /// `unrouted` if there is none. `unrouted` runs before `src` can be
+    /// registered, so a listener that registers a peer and then empties its
+    /// own queue has seen everything the peer sent before.
+    pub(crate) fn route(&self, src: SocketAddr, datagram: Vec<u8>, unrouted: impl FnOnce(Vec<u8>)) {
+        let mut connections = self.connections.lock().unwrap();
+        let queue = match connections.get(&src) {
+            Some(queue) => queue,
+            None => return unrouted(datagram),
+        };
+        if !queue.push(datagram) {
+            connections.remove(&src); // Connection went away without unregistering
+        }
+    }
+}
+
+/// Handshakes a listener has started but not finished, keyed by peer.
+///
+/// A listener's accept calls feed them the datagrams of their peers and the
+/// passage of time, as a driver does for an established connection, so every
+/// handshake retransmits and gives up on its own schedule and a peer that
+/// never completes one holds up nobody else.
+pub(crate) struct Handshakes {
+    half_open: HashMap<SocketAddr, Connection>,
+    /// Datagrams to send from the listening socket, with their destination.
+    transmits: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+impl Handshakes {
+    pub(crate) fn new() -> Self {
+        Handshakes {
+            half_open: HashMap::new(),
+            transmits: VecDeque::new(),
+        }
+    }
+
+    /// Hands a datagram from a peer without an established connection to
+    /// the handshake in progress with it, or to `listener` to start one.
+    /// Returns the connection once its handshake is complete.
+    pub(crate) fn handle_datagram(&mut self, listener: &Connection, src: SocketAddr, datagram: &[u8], now: Instant) -> std::io::Result<Option<Connection>> {
+        if let Some(connection) = self.half_open.get_mut(&src) {
+            if connection.handle_datagram(datagram, now).is_err() {
+                self.half_open.remove(&src);
+                return Ok(None);
+            }
+            return Ok(self.progress(src));
+        }
+        let action = if self.half_open.len() < MAX_HALF_OPEN {
+            listener.accept_datagram(src, datagram, now)?
+        } else {
+            listener.accept_datagram_with_cookies(src, datagram, now)?
+        };
+        match action {
+            ListenerAction::Ignore => {}
+            ListenerAction::Reply(bytes) => self.transmits.push_back((src, bytes)),
+            ListenerAction::Accept(connection) => {
+                self.half_open.insert(src, *connection);
+                return Ok(self.progress(src));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fires the timers of every handshake, forgetting those that gave up.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let due: Vec<SocketAddr> = self
+            .half_open
+            .iter()
+            .filter(|(_, connection)| connection.poll_timeout().is_some_and(|at| at <= now))
+            .map(|(&src, _)| src)
+            .collect();
+        for src in due {
+            let connection = self.half_open.get_mut(&src).unwrap();
+            if connection.handle_timeout(now).is_err() {
+                self.half_open.remove(&src);
+                continue;
+            }
+            self.progress(src);
+        }
+    }
+
+    /// Returns when `handle_timeout` should next be called, if any handshake is open.
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.half_open.values().filter_map(Connection::poll_timeout).min()
+    }
+
+    /// Returns the next datagram to send and its destination.
+    pub(crate) fn poll_transmit(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        self.transmits.pop_front()
+    }
+
+    /// Collects what the handshake with `src` wants sent, and takes it out
+    /// of the table once it is complete or has given up.
+    fn progress(&mut self, src: SocketAddr) -> Option<Connection> {
+        let connection = self.half_open.get_mut(&src)?;
+        while let Some(bytes) = connection.poll_transmit() {
+            self.transmits.push_back((src, bytes));
+        }
+        match connection.state() {
+            ConnectionState::SynReceived => None,
+            ConnectionState::Closed => {
+                self.half_open.remove(&src);
+                None
+            }
+            _ => self.half_open.remove(&src),
+        }
+    }
+}
+
+/// What the accept calls of a listener share: datagrams from peers without
+/// a connection, and the handshakes in progress with them.
+pub(crate) struct Backlog<R> {
+    /// Datagrams the demultiplexer could not route.
+    pub(crate) pending: R,
+    /// Datagrams taken from `pending` early, while handing a connection over.
+    pub(crate) deferred: VecDeque<PendingDatagram>,
+    pub(crate) handshakes: Handshakes,
+}
+
+impl<R> Backlog<R> {
+    pub(crate) fn new(pending: R) -> Self {
+        Backlog {
+            pending,
+            deferred: VecDeque::new(),
+            handshakes: Handshakes::new(),
+        }
+    }
+
+    /// Takes out what `peer` sent right behind the datagram that completed
+    /// its handshake, which reached the listener before the peer's route
+    /// existed. `queued` is what has been taken from `pending` since; the
+    /// rest of it is deferred to the next accept.
+    pub(crate) fn take_early(&mut self, peer: SocketAddr, queued: Vec<PendingDatagram>) -> Vec<Vec<u8>> {
+        let mut early = Vec::new();
+        let waiting: Vec<PendingDatagram> = self.deferred.drain(..).chain(queued).collect();
+        for (src, datagram) in waiting {
+            if src == peer {
+                early.push(datagram);
+            } else {
+                self.deferred.push_back((src, datagram));
+            }
+        }
+        early
+    }
+}
+
+/// Routes datagrams arriving on a listening UDP socket to per-connection
+/// queues keyed by source address.
+pub(crate) struct Demux {
+    socket: UdpSocket,
+    routes: Routes<SyncSender<Vec<u8>>>,
+}
+
+impl Demux {
+    /// Starts demultiplexing `socket` on a background thread. Datagrams from
+    /// addresses without a registered connection are sent to the returned
+    /// queue. The thread exits once the listener and all of its connections
+    /// have been dropped.
+    pub(crate) fn spawn(socket: UdpSocket) -> std::io::Result<(Arc<Demux>, Receiver<PendingDatagram>)> {
+        socket.set_read_timeout(Some(DEMUX_POLL_INTERVAL))?;
+        let demux = Arc::new(Demux {
+            socket,
+            routes: Routes::new(),
+        });
+        let (pending_tx, pending_rx) = mpsc::sync_channel(PENDING_QUEUE_LEN);
+        let worker = Arc::clone(&demux);
+        thread::spawn(move || worker.run(pending_tx));
+        Ok((demux, pending_rx))
+    }
+
+    fn run(self: Arc<Self>, pending: SyncSender<PendingDatagram>) {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        // Once only this thread holds the demultiplexer, nobody can use it.
+        while Arc::strong_count(&self) > 1 {
+            let (amt, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    eprintln!("Demultiplexer stopped: {}", e);
+                    return;
+                }
+            };
+            // The listener may be gone while its connections live on, or too
+            // far behind, in which case the datagram is dropped.
+            self.routes.route(src, buf[..amt].to_vec(), |datagram| {
+                let _ = pending.try_send((src, datagram));
+            });
+        }
+    }
+
+    /// Routes all future datagrams from `peer` to the returned queue, or
+    /// returns `None` if a live connection already owns that address.
+    pub(crate) fn register(&self, peer: SocketAddr) -> Option<Receiver<Vec<u8>>> {
+        let (tx, rx) = mpsc::sync_channel(CONNECTION_QUEUE_LEN);
+        self.routes.register(peer, tx).then_some(rx)
+    }
+
+    /// Sends a datagram from the listening socket without any connection state.
+    pub(crate) fn send_to(&self, bytes: &[u8], peer: SocketAddr) -> std::io::Result<usize> {
+        self.socket.send_to(bytes, peer)
+    }
+
+    fn unregister(&self, peer: SocketAddr) {
+        self.routes.unregister(peer);
+    }
+}
+
+/// The datagram path underneath a `StyxSocket`.
+pub(crate) enum Transport {
+    /// A UDP socket of our own, connected to the peer (the client side).
+    Connected(UdpSocket),
+    /// A listening socket whose datagrams are demultiplexed by source address.
+    Listening {
+        demux: Arc<Demux>,
+        backlog: Mutex<Backlog<Receiver<PendingDatagram>>>,
+    },
+    /// A connection accepted by a listener. Sends go out through the shared
+    /// socket; datagrams arrive through the listener's demultiplexer.
+    Demuxed {
+        demux: Arc<Demux>,
+        peer: SocketAddr,
+        incoming: Receiver<Vec<u8>>,
+        read_timeout: Option<Duration>,
+    },
+}
+
+impl Transport {
+    /// Sends one datagram to the peer.
+    pub(crate) fn send(&self, bytes: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Connected(socket) => socket.send(bytes),
+            Transport::Demuxed { demux, peer, .. } => demux.send_to(bytes, *peer),
+            Transport::Listening { .. } => Err(not_connected()),
+        }
+    }
+
+    /// Receives one datagram from the peer, honouring the read timeout.
+    /// A timeout is reported as `WouldBlock`, like a `UdpSocket` on Unix.
+    pub(crate) fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Connected(socket) => socket.recv(buf),
+            Transport::Demuxed { incoming, read_timeout, .. } => {
+                let datagram = match read_timeout {
+                    Some(timeout) => incoming.recv_timeout(*timeout).map_err(|e| match e {
+                        RecvTimeoutError::Timeout => std::io::Error::new(std::io::ErrorKind::WouldBlock, "Timed out waiting for peer"),
+                        RecvTimeoutError::Disconnected => listener_gone(),
+                    })?,
+                    None => incoming.recv().map_err(|_| listener_gone())?,
+                };
+                let amt = datagram.len().min(buf.len());
+                buf[..amt].copy_from_slice(&datagram[..amt]);
+                Ok(amt)
+            }
+            Transport::Listening { .. } => Err(not_connected()),
+        }
+    }
+
+    /// Returns the local address datagrams are sent from.
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Transport::Connected(socket) => socket.local_addr(),
+            Transport::Listening { demux, .. } | Transport::Demuxed { demux, .. } => demux.socket.local_addr(),
+        }
+    }
+
+    /// Sets how long `recv` blocks before giving up; `None` waits forever.
+    pub(crate) fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Transport::Connected(socket) => socket.set_read_timeout(timeout),
+            Transport::Demuxed { read_timeout, .. } => {
+                *read_timeout = timeout;
+                Ok(())
+            }
+            Transport::Listening { .. } => Err(not_connected()),
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        if let Transport::Demuxed { demux, peer, .. } = self {
+            demux.unregister(*peer);
+        }
+    }
+}
+
+/// Sets the Don't Fragment bit on everything `socket` sends and keeps the
+/// OS from fragmenting datagrams itself, so a path MTU probe that is too
+/// large for the path is lost instead of arriving in pieces (RFC 8899).
+#[cfg(target_os = "linux")]
+pub(crate) fn disable_fragmentation(socket: &impl std::os::fd::AsRawFd, local: SocketAddr) -> std::io::Result<()> {
+    let (level, name, value) = match local {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE),
+    };
+    let value = value as libc::c_int;
+    // SAFETY: the descriptor is open for as long as `socket` is borrowed,
+    // and the option value is a c_int as these options expect.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Elsewhere the OS decides whether large datagrams are fragmented. Path MTU
+/// discovery still works, but may settle on a size that is fragmented.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn disable_fragmentation<S>(_socket: &S, _local: SocketAddr) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Whether a failed send of `len` bytes only means the datagram was larger
+/// than the OS lets through without fragmenting it (`EMSGSIZE`). Such a
+/// datagram is treated as lost on the way, which is what path MTU probing
+/// and black hole detection expect of a datagram that is too large. Any
+/// other error is a real one.
+#[cfg(target_os = "linux")]
+pub(crate) fn dropped_as_too_large(e: &std::io::Error, len: usize) -> bool {
+    len > crate::pmtu::BASE_DATAGRAM_SIZE && e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// Elsewhere fragmentation stays enabled, so no datagram we send is refused
+/// for its size and every send error is a real one.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn dropped_as_too_large(_e: &std::io::Error, _len: usize) -> bool {
+    false
+}
+
+fn not_connected() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotConnected, "Socket is not connected to a peer")
+}
+
+pub(crate) fn listener_gone() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Listener demultiplexer stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::RetransmitPolicy;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn routes_datagrams_by_source_address() {
+        let routes = Routes::new();
+        let (tx, rx) = mpsc::sync_channel(2);
+        assert!(routes.register(addr(1), tx));
+        let (other, _) = mpsc::sync_channel(2);
+        assert!(!routes.register(addr(1), other), "the address is already owned");
+
+        let unrouted = std::cell::RefCell::new(Vec::new());
+        let route = |src, datagram| routes.route(src, datagram, |datagram| unrouted.borrow_mut().push(datagram));
+
+        route(addr(1), vec![1]);
+        assert_eq!(rx.try_recv(), Ok(vec![1]));
+        route(addr(2), vec![2]);
+        assert_eq!(unrouted.take(), [vec![2]], "unknown peers go back to the listener");
+
+        // A full queue drops datagrams instead of growing.
+        for datagram in 3..6 {
+            route(addr(1), vec![datagram]);
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [vec![3], vec![4]]);
+
+        // A connection that went away loses its route with the next datagram.
+        drop(rx);
+        route(addr(1), vec![6]);
+        route(addr(1), vec![7]);
+        assert_eq!(unrouted.take(), [vec![7]]);
+    }
+
+    #[test]
+    fn dropping_a_connection_unregisters_its_peer() {
+        let (demux, _pending) = Demux::spawn(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+        let incoming = demux.register(addr(1)).unwrap();
+        assert!(demux.register(addr(1)).is_none());
+        let transport = Transport::Demuxed {
+            demux: Arc::clone(&demux),
+            peer: addr(1),
+            incoming,
+            read_timeout: None,
+        };
+        drop(transport);
+        assert!(demux.register(addr(1)).is_some());
+    }
+
+    /// Sends `datagram` from `src` and returns what the handshakes sent to `src` in reply.
+    fn exchange(handshakes: &mut Handshakes, listener: &Connection, src: SocketAddr, datagram: &[u8], now: Instant) -> (Option<Connection>, Vec<Vec<u8>>) {
+        let accepted = handshakes.handle_datagram(listener, src, datagram, now).unwrap();
+        let replies = std::iter::from_fn(|| handshakes.poll_transmit()).filter(|(dst, _)| *dst == src).map(|(_, bytes)| bytes).collect();
+        (accepted, replies)
+    }
+
+    #[test]
+    fn a_silent_peer_does_not_hold_up_other_handshakes() {
+        let now = Instant::now();
+        let listener = Connection::listen(addr(1)).unwrap();
+        let mut handshakes = Handshakes::new();
+
+        // A SYN whose sender never answers the SYN-ACK.
+        let mut silent = Connection::connect(addr(2), addr(1), RetransmitPolicy::default(), now).unwrap();
+        let (accepted, replies) = exchange(&mut handshakes, &listener, addr(2), &silent.poll_transmit().unwrap(), now);
+        assert!(accepted.is_none());
+        assert_eq!(replies.len(), 1);
+
+        // Another client completes its handshake meanwhile.
+        let mut client = Connection::connect(addr(3), addr(1), RetransmitPolicy::default(), now).unwrap();
+        let (accepted, replies) = exchange(&mut handshakes, &listener, addr(3), &client.poll_transmit().unwrap(), now);
+        assert!(accepted.is_none());
+        client.handle_datagram(&replies[0], now).unwrap();
+        let (accepted, _) = exchange(&mut handshakes, &listener, addr(3), &client.poll_transmit().unwrap(), now);
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.state(), ConnectionState::Established);
+        assert_eq!(accepted.peer_addr(), Some(addr(3)));
+
+        // The silent peer's SYN-ACK is resent until its handshake gives up.
+        let mut resent = 0;
+        while let Some(at) = handshakes.poll_timeout() {
+            handshakes.handle_timeout(at);
+            resent += std::iter::from_fn(|| handshakes.poll_transmit()).count();
+        }
+        assert_eq!(resent, RetransmitPolicy::default().max_retransmissions as usize);
+        assert!(handshakes.half_open.is_empty());
+    }
+
+    #[test]
+    fn a_full_table_falls_back_to_syn_cookies() {
+        let now = Instant::now();
+        let listener = Connection::listen(addr(1)).unwrap();
+        let mut handshakes = Handshakes::new();
+        for port in 0..MAX_HALF_OPEN as u16 {
+            let mut client = Connection::connect(addr(1000 + port), addr(1), RetransmitPolicy::default(), now).unwrap();
+            handshakes.handle_datagram(&listener, addr(1000 + port), &client.poll_transmit().unwrap(), now).unwrap();
+        }
+        assert_eq!(std::iter::from_fn(|| handshakes.poll_transmit()).count(), MAX_HALF_OPEN);
+
+        // One more client still gets in, without the table growing.
+        let mut client = Connection::connect(addr(3), addr(1), RetransmitPolicy::default(), now).unwrap();
+        let (accepted, replies) = exchange(&mut handshakes, &listener, addr(3), &client.poll_transmit().unwrap(), now);
+        assert!(accepted.is_none());
+        assert_eq!(handshakes.half_open.len(), MAX_HALF_OPEN);
+        client.handle_datagram(&replies[0], now).unwrap();
+        let (accepted, _) = exchange(&mut handshakes, &listener, addr(3), &client.poll_transmit().unwrap(), now);
+        assert_eq!(accepted.unwrap().state(), ConnectionState::Established);
+        assert_eq!(handshakes.half_open.len(), MAX_HALF_OPEN);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn only_emsgsize_drops_a_large_datagram() {
+        use crate::pmtu::BASE_DATAGRAM_SIZE;
+
+        let too_large = std::io::Error::from_raw_os_error(libc::EMSGSIZE);
+        assert!(dropped_as_too_large(&too_large, MAX_DATAGRAM_SIZE));
+        assert!(!dropped_as_too_large(&too_large, BASE_DATAGRAM_SIZE));
+        for errno in [libc::ECONNREFUSED, libc::ENETUNREACH, libc::EAGAIN, libc::ENOBUFS] {
+            let e = std::io::Error::from_raw_os_error(errno);
+            assert!(!dropped_as_too_large(&e, MAX_DATAGRAM_SIZE), "errno {}", errno);
+        }
+    }
+}